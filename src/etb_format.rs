@@ -4,6 +4,15 @@ use crate::stream::Stream;
 const ETB_PACKET_SIZE: usize = 16;
 const NULL_TRACE_SOURCE: u8 = 0x00;
 
+/* create the stream of a trace ID, in the protocol given for that ID if there is one */
+fn init_id_stream(etb_stream: &Stream, id: u8) -> Stream {
+    let mut stream = etb_stream.init_new();
+    if let Some(&(_, protocol)) = etb_stream.trace_id_protocols.iter().find(|(i, _)| *i == id) {
+        stream.protocol = protocol;
+    }
+    stream
+}
+
 pub fn decode_etb_stream(etb_stream: Stream) {
     let mut id: u8;
     let fsync = [0xff, 0xff, 0xff, 0x7f];
@@ -13,7 +22,7 @@ pub fn decode_etb_stream(etb_stream: Stream) {
     let mut pre_id: Option<usize> = None;
     let mut nr_stream = 1;
     let mut stream: Vec<Stream> = Vec::new();
-    stream.push(etb_stream.init_new());
+    stream.push(init_id_stream(&etb_stream, 1));
 
     let mut end: u8;
     let mut c: u8;
@@ -67,7 +76,7 @@ pub fn decode_etb_stream(etb_stream: Stream) {
                             let nr_new = idx - nr_stream + 1;
                             nr_stream = idx + 1;
                             for i in (nr_stream - nr_new)..nr_stream {
-                                stream[i] = etb_stream.init_new();
+                                stream.push(init_id_stream(&etb_stream, (i + 1) as u8));
                             }
                         }
                     }
@@ -92,10 +101,10 @@ pub fn decode_etb_stream(etb_stream: Stream) {
 
     for i in 0..nr_stream {
         if stream[i].buff.len() != 0 {
-            println!("Decode trace stream of ID {}", i);
+            println!("Decode trace stream of ID {}", i + 1);
             stream::decode_stream(&mut stream[i]);
         } else {
-            println!("There is no valid data in the stream of ID {}", i);
+            println!("There is no valid data in the stream of ID {}", i + 1);
         }
     }
 }
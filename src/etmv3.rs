@@ -0,0 +1,255 @@
+use crate::etmv4::decode_continuation_field;
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::ptm::*;
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_etmv3::*;
+use crate::tracer_ptm::{PtmAtomType::*, *};
+
+const C_BIT: u8 = 0x80;
+
+/* the packets which ETMv3 shares with PTM are decoded by the PTM decoders */
+pub fn get_etmv3_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "async" => Some(decode_ptm_async),
+        "cycle_count" => Some(decode_etmv3_cycle_count),
+        "isync" | "isync_cycle_count" => Some(decode_etmv3_isync),
+        "trigger" => Some(decode_ptm_trigger),
+        "vmid" => Some(decode_ptm_vmid),
+        "timestamp" => Some(decode_ptm_timestamp),
+        "ignore" => Some(decode_ptm_ignore),
+        "context_id" => Some(decode_ptm_context_id),
+        "exception_exit" => Some(decode_ptm_exception_return),
+        "exception_entry" => Some(decode_etmv3_exception_entry),
+        "branch_address" => Some(decode_etmv3_branch_address),
+        "p_header_format_1" | "p_header_format_2" => Some(decode_etmv3_p_header),
+        _ => None,
+    }
+}
+
+/* read a context ID of the configured size, little-endian */
+fn decode_etmv3_context_id_field(stream: &Stream, offset: usize) -> Result<u32, &'static str> {
+    let size = stream.ptm_tracer.context_id_size as usize;
+    let bytes = stream
+        .buff
+        .get(offset..offset + size)
+        .ok_or("Truncated context ID")?;
+    Ok(bytes
+        .iter()
+        .enumerate()
+        .fold(0, |id, (i, &b)| id | ((b as u32) << (8 * i))))
+}
+
+pub fn decode_etmv3_cycle_count(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (count, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 5)
+        .ok_or("More than 5 bytes in the cycle count packet")?;
+
+    tracer_etmv3_cycle_count(&stream.ptm_tracer, count);
+
+    Ok(1 + len)
+}
+
+/*
+ * I-sync packets: the header, the cycle count if the header is 0x70, the context ID, the
+ * information byte and then the address, whose bit[0] is the Thumb state. If bit[7] of the
+ * information byte, LSiP, is set, a load or store multiple instruction was in progress and its
+ * address follows, compressed against the I-sync address as in a branch address packet.
+ */
+pub fn decode_etmv3_isync(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let mut index = 1;
+    let mut cycle_count = None;
+    let mut context_id = None;
+
+    if stream.buff[pkt_offset] == 0x70 {
+        let (count, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 5)
+            .ok_or("More than 5 bytes of cycle count in the I-sync packet")?;
+        cycle_count = Some(count);
+        index += len;
+    }
+
+    if stream.ptm_tracer.context_id_size != 0 {
+        context_id = Some(decode_etmv3_context_id_field(stream, pkt_offset + index)?);
+        index += stream.ptm_tracer.context_id_size as usize;
+    }
+
+    let info = *stream
+        .buff
+        .get(pkt_offset + index)
+        .ok_or("Truncated I-sync packet")?;
+    index += 1;
+    let address = stream
+        .buff
+        .get(pkt_offset + index..pkt_offset + index + 4)
+        .ok_or("Truncated I-sync packet")?;
+    let address = u32::from_le_bytes([address[0], address[1], address[2], address[3]]);
+    index += 4;
+    /* bit[4] of the information byte is the Jazelle state */
+    let inst_set = if (info & 0x10) != 0 {
+        PtmInstSet::Jazelle
+    } else if (address & 1) != 0 {
+        PtmInstSet::Thumb
+    } else {
+        PtmInstSet::Arm
+    };
+
+    let mut lsip_address = None;
+    if (info & 0x80) != 0 {
+        let (lsip, _, has_exception, len) =
+            decode_ptm_address(&stream.buff, pkt_offset + index, address & !1, inst_set)?;
+        if has_exception {
+            return Err("Exception information after the load/store in progress address");
+        }
+        lsip_address = Some(lsip);
+        index += len;
+    }
+
+    tracer_ptm_isync(
+        &mut stream.ptm_tracer,
+        address & !1,
+        inst_set,
+        info,
+        cycle_count,
+        context_id,
+        lsip_address,
+    );
+
+    Ok(index)
+}
+
+pub fn decode_etmv3_exception_entry(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_etmv3_exception_entry(&stream.ptm_tracer);
+    Ok(1)
+}
+
+pub fn decode_etmv3_branch_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (address, inst_set, has_exception, mut index) = decode_ptm_address(
+        &stream.buff,
+        pkt_offset,
+        stream.ptm_tracer.address,
+        stream.ptm_tracer.inst_set,
+    )?;
+    let mut exception = None;
+
+    if has_exception {
+        /*
+         * C | 0 | Can | Exception[3:0] | NS, then C | 0 | Hyp | Exception[8:4],
+         * then a resume byte which only matters for Jazelle
+         */
+        let data1 = *stream
+            .buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated exception information in the branch address packet")?;
+        index += 1;
+        let mut number = ((data1 >> 1) & 0x0f) as u16;
+        let mut hyp = false;
+        if (data1 & C_BIT) != 0 {
+            let data2 = *stream
+                .buff
+                .get(pkt_offset + index)
+                .ok_or("Truncated exception information in the branch address packet")?;
+            index += 1;
+            number |= ((data2 & 0x1f) as u16) << 4;
+            hyp = (data2 & 0x20) != 0;
+            if (data2 & C_BIT) != 0 {
+                stream
+                    .buff
+                    .get(pkt_offset + index)
+                    .ok_or("Truncated exception information in the branch address packet")?;
+                index += 1;
+            }
+        }
+        exception = Some((number, (data1 & 0x01) != 0, hyp, (data1 & 0x20) != 0));
+    }
+
+    tracer_ptm_branch_address(&mut stream.ptm_tracer, address, inst_set, exception, None);
+
+    Ok(index)
+}
+
+/*
+ * Format 1 P-header, 0b1NEEEE00: EEEE E atoms followed by N N atoms.
+ * Format 2 P-header, 0b1000FF10: two atoms, bit[3] the first and bit[2] the second,
+ * 1'b0 for E and 1'b1 for N.
+ * In cycle-accurate mode, 0b10000000 is the format 0 P-header, a cycle without an instruction.
+ */
+pub fn decode_etmv3_p_header(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let mut atoms = Vec::new();
+    let mut nr_w = 0;
+
+    if (header & 0x03) == 0x02 {
+        atoms.push((header & 0x08) == 0);
+        atoms.push((header & 0x04) == 0);
+    } else if stream.ptm_tracer.cycle_accurate && header == 0x80 {
+        nr_w = 1;
+    } else {
+        atoms.resize(((header >> 2) & 0x0f) as usize, true);
+        if (header & 0x40) != 0 {
+            atoms.push(false);
+        }
+    }
+
+    /* in cycle-accurate mode each atom also takes a cycle */
+    let cycle_count = if stream.ptm_tracer.cycle_accurate {
+        Some(1)
+    } else {
+        None
+    };
+    for executed in atoms {
+        let atom = if executed { PtmAtomE } else { PtmAtomN };
+        tracer_ptm_atom(&stream.ptm_tracer, atom, cycle_count);
+    }
+    if nr_w != 0 {
+        tracer_etmv3_wait(&stream.ptm_tracer, nr_w);
+    }
+
+    Ok(1)
+}
+
+pub const ETMV3PKTS: [TracePkt; 14] = [
+    TracePkt::new("async", 0xff, 0x00),
+    TracePkt::new("cycle_count", 0xff, 0x04),
+    TracePkt::new("isync", 0xff, 0x08),
+    TracePkt::new("trigger", 0xff, 0x0c),
+    TracePkt::new("vmid", 0xff, 0x3c),
+    TracePkt::new("timestamp", 0xfb, 0x42),
+    TracePkt::new("ignore", 0xff, 0x66),
+    TracePkt::new("context_id", 0xff, 0x6e),
+    TracePkt::new("isync_cycle_count", 0xff, 0x70),
+    TracePkt::new("exception_exit", 0xff, 0x76),
+    TracePkt::new("exception_entry", 0xff, 0x7e),
+    TracePkt::new("branch_address", 0x01, 0x01),
+    TracePkt::new("p_header_format_2", 0xf3, 0x82),
+    TracePkt::new("p_header_format_1", 0x83, 0x80),
+];
+
+pub fn etmv3_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    let mut from = 0;
+    while let Some(i) = ptm_find_async(&stream.buff, from) {
+        /* an I-sync packet right after the A-sync gives the first address */
+        if matches!(stream.buff.get(i + 6), Some(0x08) | Some(0x70)) {
+            /* SYNCING -> INSYNC */
+            stream.state = InSync;
+            return Ok(i);
+        }
+        from = i + 1;
+    }
+    Err("No I-sync packet right after an A-sync packet")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isync_with_load_store_in_progress() {
+        let mut stream = Stream::new();
+        stream.ptm_tracer.context_id_size = 0;
+        /* LSiP and tracing enabled, address 0x1000, then 0x1010 in one compressed byte */
+        stream.buff = vec![0x08, 0xa0, 0x00, 0x10, 0x00, 0x00, 0x09];
+
+        assert_eq!(decode_etmv3_isync(0, &mut stream), Ok(7));
+        assert_eq!(stream.ptm_tracer.address, 0x1000);
+        assert_eq!(stream.ptm_tracer.lsip_address, Some(0x1010));
+    }
+}
@@ -1,19 +1,165 @@
-use crate::pktproto::TracePkt;
+use crate::pktproto::{DecodeFunc, TracePkt};
 use crate::stream::State::InSync;
 use crate::stream::{State, Stream};
-use crate::tracer_etmv4::{AddrReg::*, AtomType::*, *};
+use crate::tracer_etmv4::{AddrReg::*, AtomType::*, SecurityState::*, *};
 
 const C_BIT: u8 = 0x80;
 
-pub fn get_decode_func(
-    pkt_name: &str,
-) -> Option<fn(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str>> {
+/* the latest ETMv4 minor architecture version the decoder knows of, ETMv4.6 */
+pub const ETMV4_LATEST_VERSION: u8 = 6;
+
+/*
+ * Read a field which spans up to max_bytes bytes. Each byte carries 7 bits of the field
+ * and a continuation bit telling whether another byte follows.
+ * Return the field value and the number of bytes it occupies, or None if the field is
+ * longer than max_bytes or runs past the end of the buffer.
+ */
+pub fn decode_continuation_field(
+    buff: &[u8],
+    offset: usize,
+    max_bytes: usize,
+) -> Option<(u32, usize)> {
+    let mut value = 0;
+
+    for i in 0..max_bytes {
+        let data = *buff.get(offset + i)?;
+        value |= ((data & !C_BIT) as u32) << (7 * i);
+        if (data & C_BIT) == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/* read a little-endian field of size bytes, as the VMID and context ID fields are encoded */
+fn decode_sized_field(buff: &[u8], offset: usize, size: u32) -> Option<u32> {
+    let mut value = 0;
+
+    for i in 0..size as usize {
+        value |= (*buff.get(offset + i)? as u32) << (8 * i);
+    }
+    Some(value)
+}
+
+/* packets which ETE has removed from ETMv4 */
+const ETE_REMOVED_PKTS: [&str; 9] = [
+    "data_sync_marker",
+    "cond_inst_format_1",
+    "cond_inst_format_2",
+    "cond_inst_format_3",
+    "cond_flush",
+    "cond_result_format_1",
+    "cond_result_format_2",
+    "cond_result_format_3",
+    "cond_result_format_4",
+];
+
+/* packets which only ETE has, their headers are reserved in ETMv4 */
+const ETE_ONLY_PKTS: [&str; 5] = [
+    "instrumentation",
+    "transaction_start",
+    "transaction_commit",
+    "timestamp_marker",
+    "source_address",
+];
+
+/* packets which were added after ETMv4.0, with the minor version which added them */
+const ETMV4_MIN_VERSION_PKTS: [(&str, u8); 1] = [("function_return", 2)];
+
+/* packets which are only traced for M-profile PEs */
+const M_PROFILE_ONLY_PKTS: [&str; 1] = ["function_return"];
+
+/* the name of the architecture the trace stream is decoded for */
+pub fn etmv4_arch_name(stream: &Stream) -> String {
+    if stream.tracer.ete {
+        String::from("ETE")
+    } else {
+        format!("ETMv4.{}", stream.etmv4_version)
+    }
+}
+
+/* tell if a packet exists in the architecture the trace stream is decoded for */
+pub fn etmv4_packet_supported(pkt_name: &str, stream: &Stream) -> bool {
+    if !stream.tracer.m_profile && M_PROFILE_ONLY_PKTS.contains(&pkt_name) {
+        return false;
+    }
+    if stream.tracer.ete {
+        !ETE_REMOVED_PKTS.contains(&pkt_name)
+    } else {
+        !ETE_ONLY_PKTS.contains(&pkt_name)
+    }
+}
+
+/* tell if a packet exists in the ETMv4 minor version the trace stream is decoded for */
+pub fn etmv4_packet_in_version(pkt_name: &str, stream: &Stream) -> bool {
+    stream.tracer.ete
+        || ETMV4_MIN_VERSION_PKTS
+            .iter()
+            .all(|(name, version)| *name != pkt_name || stream.etmv4_version >= *version)
+}
+
+/* find the packet of a header, None if the header is reserved in the architecture decoded for */
+pub fn etmv4_find_packet(header: u8, stream: &Stream) -> Option<&'static TracePkt> {
+    TRACEPKTS
+        .iter()
+        .find(|pkt| (header & pkt.mask) == pkt.val)
+        .filter(|pkt| {
+            etmv4_packet_supported(pkt.name, stream) && etmv4_packet_in_version(pkt.name, stream)
+        })
+}
+
+/*
+ * Check the context information byte of a context or address with context packet.
+ * Bits [3:2] are RES0 in every ETMv4 version, and a PE can only be at Secure EL2
+ * since ETMv4.4 (ARMv8.4-A). ETE traces ARMv9-A PEs and uses bit[3] as NSE for RME,
+ * where EL3 is either Secure or Root and only EL3 can be in Root state.
+ */
+fn check_context_info(data: u8, stream: &Stream) -> Result<(), &'static str> {
+    let reserved = if stream.tracer.ete { 0x04 } else { 0x0c };
+    if (data & reserved) != 0 {
+        return Err("Reserved bits set in the context information");
+    }
+    if stream.tracer.ete {
+        let el3 = (data & 0x3) == 3;
+        if el3 && (data & 0x20) != 0 {
+            return Err("EL3 in Non-secure or Realm state in the context information");
+        }
+        if !el3 && (data & 0x28) == 0x08 {
+            return Err("Root state below EL3 in the context information");
+        }
+    } else if (data & 0x3) == 2 && (data & 0x20) == 0 && stream.etmv4_version < 4 {
+        return Err("Secure EL2 in the context information requires ETMv4.4 or later");
+    }
+    Ok(())
+}
+
+/* check the decoder configuration against the configured architecture version */
+pub fn etmv4_check_version(stream: &Stream) -> Result<(), String> {
+    if stream.etmv4_version > ETMV4_LATEST_VERSION {
+        return Err(format!(
+            "Unsupported architecture version ETMv4.{}",
+            stream.etmv4_version
+        ));
+    }
+    if !stream.tracer.ete
+        && stream.etmv4_version < 1
+        && (stream.tracer.vmid_size > 1 || stream.tracer.vmidopt)
+    {
+        return Err(String::from(
+            "VMIDs wider than 8 bits and VMIDOPT require ETMv4.1 or later",
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
     match pkt_name {
         "extension" => Some(decode_extension),
         "trace_info" => Some(decode_trace_info),
         "trace_on" => Some(decode_trace_on),
         "timestamp" => Some(decode_timestamp),
         "exception" => Some(decode_exception),
+        "function_return" => Some(decode_function_return),
         "cc_format_1" => Some(decode_cc_format_1),
         "cc_format_2" => Some(decode_cc_format_2),
         "cc_format_3" => Some(decode_cc_format_3),
@@ -21,6 +167,11 @@ pub fn get_decode_func(
         "commit" => Some(decode_commit),
         "cancel_format_1" | "cancel_format_2" | "cancel_format_3" => Some(decode_cancel),
         "mispredict" => Some(decode_mispredict),
+        "instrumentation" => Some(decode_instrumentation),
+        "timestamp_marker" => Some(decode_timestamp_marker),
+        "transaction_start" => Some(decode_transaction_start),
+        "transaction_commit" => Some(decode_transaction_commit),
+        "source_address" => Some(decode_source_address),
         "cond_inst_format_1" => Some(decode_cond_inst_format_1),
         "cond_inst_format_2" => Some(decode_cond_inst_format_2),
         "cond_inst_format_3" => Some(decode_cond_inst_format_3),
@@ -82,12 +233,18 @@ pub fn decode_extension(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
         3 => {
             /* discard */
             index += 1;
-            tracer_discard(&stream.tracer);
+            if stream.state >= State::InSync {
+                tracer_discard(&mut stream.tracer);
+            }
         }
         5 => {
             /* overflow */
             index += 1;
-            tracer_overflow(&stream.tracer);
+            if stream.state >= State::InSync {
+                tracer_overflow(&mut stream.tracer);
+                /* trace has been lost, so wait for the next TraceInfo before decoding again */
+                stream.state = State::Syncing;
+            }
         }
         _ => {
             return Err("Invalid async packet: First payload byte of async is not correct");
@@ -98,94 +255,48 @@ pub fn decode_extension(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
 
 pub fn decode_trace_info(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
     let mut index = 1;
-    let mut i;
-    let mut plctl = 0;
     let mut info = 0;
     let mut key = 0;
     let mut spec = 0;
     let mut cyct = 0;
-    let mut data;
 
-    /* TODO: refactor the following code into a reusable function */
-    i = 0;
-    while i < 4 {
-        data = stream.buff[pkt_offset + index];
-        index += 1;
-        plctl |= ((data & !C_BIT) as u32) << (7 * i);
-        if (data & C_BIT) == 0 {
-            break;
-        }
-        i += 1;
-    }
-    if i >= 1 {
-        return Err("More than 1 PLCTL field in the trace info packet");
-    }
+    let (plctl, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 1)
+        .ok_or("More than 1 PLCTL field in the trace info packet")?;
+    index += len;
 
     if (plctl & 1) != 0 {
-        i = 0;
-        /* the INFO section is present*/
-        while i < 4 {
-            data = stream.buff[pkt_offset + index];
-            index += 1;
-            info |= ((data & !C_BIT) as u32) << (7 * i);
-            if (data & C_BIT) == 0 {
-                break;
-            }
-        }
-        if i >= 1 {
-            return Err("More than 1 INFO field in the trace info packet");
-        }
+        /* the INFO section is present, ETE widens it to 2 fields */
+        let max_fields = if stream.tracer.ete { 2 } else { 1 };
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, max_fields)
+            .ok_or("Too many INFO fields in the trace info packet")?;
+        info = field;
+        index += len;
     }
 
     if (plctl & 2) != 0 {
         /* the KEY section is present*/
-        i = 0;
-        while i < 4 {
-            data = stream.buff[pkt_offset + index];
-            index += 1;
-            key |= ((data & !C_BIT) as u32) << (7 * i);
-            if (data & C_BIT) == 0 {
-                break;
-            }
-            i += 1;
-        }
-        if i >= 4 {
-            /* 4 fileds are enough since p0_key_max is a 32-bit integer */
-            return Err("More than 4 KEY fields in the trace info packet");
-        }
+        /* 4 fileds are enough since p0_key_max is a 32-bit integer */
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 4)
+            .ok_or("More than 4 KEY fields in the trace info packet")?;
+        key = field;
+        index += len;
     }
 
     if (plctl & 4) != 0 {
         /* the SPEC section is present*/
-        i = 0;
-        while i < 4 {
-            data = stream.buff[pkt_offset + index];
-            index += 1;
-            spec |= ((data & !C_BIT) as u32) << (7 * i);
-            if (data & C_BIT) == 0 {
-                break;
-            }
-        }
-        if i >= 4 {
-            /* 4 fileds are enough since max_spec_depth is a 32-bit integer */
-            return Err("More than 4 SPEC fields in the trace info packet");
-        }
+        /* 4 fileds are enough since max_spec_depth is a 32-bit integer */
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 4)
+            .ok_or("More than 4 SPEC fields in the trace info packet")?;
+        spec = field;
+        index += len;
     }
 
     if (plctl & 8) != 0 {
         /* the CYCT section is present*/
-        i = 0;
-        while i < 2 {
-            data = stream.buff[pkt_offset + index];
-            index += 1;
-            cyct |= ((data & !C_BIT) as u32) << (7 * i);
-            if (data & C_BIT) == 0 {
-                break;
-            }
-        }
-        if i >= 2 {
-            return Err("More than 2 CYCT fields in the trace info packet\n");
-        }
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 2)
+            .ok_or("More than 2 CYCT fields in the trace info packet")?;
+        cyct = field;
+        index += len;
     }
 
     if stream.state >= State::InSync {
@@ -196,7 +307,7 @@ pub fn decode_trace_info(pkt_offset: usize, stream: &mut Stream) -> Result<usize
 }
 
 pub fn decode_trace_on(_: usize, stream: &mut Stream) -> Result<usize, &str> {
-    tracer_trace_on(&stream.tracer);
+    tracer_trace_on(&mut stream.tracer);
     return Ok(1);
 }
 
@@ -254,7 +365,7 @@ pub fn decode_exception(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
 
     if (stream.buff[pkt_offset] & 1) != 0 {
         /* exception return packet */
-        tracer_exception_return(&stream.tracer);
+        tracer_exception_return(&mut stream.tracer);
     } else {
         /* exception patcket */
         data1 = stream.buff[pkt_offset + index];
@@ -264,12 +375,13 @@ pub fn decode_exception(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
             index += 1;
         }
         ee = ((data1 & 0x40) >> 5) | (data1 & 0x01);
-        tp = ((data1 & 0x3E) >> 1) | (data2 & 0x1F);
+        tp = (((data1 & 0x3E) >> 1) as usize) | (((data2 & 0x1F) as usize) << 5);
 
         if ee != 1 && ee != 2 {
             return Err("Invalid EE in the exception packet");
         } else if ee == 2 {
-            /* there is an address packet */
+            /* there is an address packet, which is not the target of the previous branch */
+            tracer_resolve_branch(&mut stream.tracer);
             data1 = stream.buff[pkt_offset + index];
             let mut packet = None;
             for tracepkt in &TRACEPKTS {
@@ -279,12 +391,15 @@ pub fn decode_exception(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
                 }
             }
             if let Some(pkt) = packet {
-                match get_decode_func(pkt.name).unwrap()(index + pkt_offset, stream) {
+                stream.tracer.exception_address = true;
+                let ret = get_decode_func(pkt.name).unwrap()(index + pkt_offset, stream)
+                    .map_err(|msg| eprintln!("{}", msg));
+                stream.tracer.exception_address = false;
+                match ret {
                     Ok(idx) => {
                         index += idx;
                     }
-                    Err(msg) => {
-                        eprintln!("{}", msg);
+                    Err(_) => {
                         return Err("Invalid address packet in the exception packet");
                     }
                 }
@@ -292,65 +407,337 @@ pub fn decode_exception(pkt_offset: usize, stream: &mut Stream) -> Result<usize,
                 return Err("Invalid address packet in the exception packet");
             }
         }
-        tracer_exception(&mut stream.tracer, tp as usize);
+        tracer_exception(&mut stream.tracer, tp);
     }
     return Ok(index);
 }
 
-pub fn decode_cc_format_1(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_cc_format_1(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let mut index = 1;
+    let u = (stream.buff[pkt_offset] & 1) != 0;
+    let mut commit = 0;
+    let mut count = 0;
+
+    if stream.tracer.commopt == 0 {
+        /* the COMMIT section is present */
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 4)
+            .ok_or("More than 4 COMMIT fields in the cycle count format 1 packet")?;
+        commit = field;
+        index += len;
+    }
+
+    if !u {
+        /* the COUNT section is present since the U bit in the header is 1'b0 */
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 3)
+            .ok_or("More than 3 COUNT fields in the cycle count format 1 packet")?;
+        count = field + stream.tracer.cc_threshold;
+        index += len;
+    }
+
+    if commit != 0 {
+        tracer_commit(&mut stream.tracer, commit);
+    }
+    tracer_cc(&mut stream.tracer, u, count);
+
+    Ok(index)
+}
+
+pub fn decode_cc_format_2(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let f = stream.buff[pkt_offset] & 1;
+    let data = *stream
+        .buff
+        .get(pkt_offset + 1)
+        .ok_or("Truncated cycle count format 2 packet")?;
+    let aaaa = ((data & 0xf0) >> 4) as u32;
+    let bbbb = (data & 0x0f) as u32;
+
+    let commit = if f != 0 {
+        /* the commit count is an offset from the maximum speculation depth */
+        (stream.tracer.max_spec_depth + aaaa)
+            .checked_sub(15)
+            .ok_or("Invalid commit count in the cycle count format 2 packet")?
+    } else {
+        aaaa + 1
+    };
+
+    let count = bbbb + stream.tracer.cc_threshold;
+
+    tracer_commit(&mut stream.tracer, commit);
+    tracer_cc(&mut stream.tracer, false, count);
+
+    Ok(2)
 }
 
-pub fn decode_cc_format_2(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_cc_format_3(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let aa = ((stream.buff[pkt_offset] & 0x0c) >> 2) as u32;
+    let bb = (stream.buff[pkt_offset] & 0x03) as u32;
+    let count = bb + stream.tracer.cc_threshold;
+
+    if stream.tracer.commopt == 0 {
+        tracer_commit(&mut stream.tracer, aa + 1);
+    }
+    tracer_cc(&mut stream.tracer, false, count);
+
+    Ok(1)
 }
 
-pub fn decode_cc_format_3(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_data_sync_marker(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+
+    if (header & 0x08) == 0 {
+        /* numbered data synchronization marker */
+        tracer_numbered_data_sync_marker(&mut stream.tracer, header & 0x07);
+    } else {
+        /* unnumbered data synchronization marker */
+        let a = header & 0x07;
+        if a > 4 {
+            return Err("Reserved type of the unnumbered data synchronization marker");
+        }
+        tracer_unnumbered_data_sync_marker(&mut stream.tracer, a);
+    }
+
+    Ok(1)
 }
 
-pub fn decode_data_sync_marker(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_commit(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (commit, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 4)
+        .ok_or("More than 4 COMMIT fields in the commit packet")?;
+
+    tracer_commit(&mut stream.tracer, commit);
+
+    Ok(1 + len)
 }
 
-pub fn decode_commit(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+/* append the atoms encoded by the AA field of the cancel format 2 and mispredict packets */
+fn decode_aa_atoms(stream: &mut Stream, aa: u8) {
+    match aa {
+        1 => {
+            tracer_atom(&mut stream.tracer, AtomTypeE);
+        }
+        2 => {
+            tracer_atom(&mut stream.tracer, AtomTypeE);
+            tracer_atom(&mut stream.tracer, AtomTypeE);
+        }
+        3 => {
+            tracer_atom(&mut stream.tracer, AtomTypeN);
+        }
+        _ => {}
+    }
 }
 
-pub fn decode_cancel(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_cancel(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+
+    if (header & 0xfe) == 0x2e {
+        /* cancel format 1: the M bit indicates a mispredict after the cancel */
+        let (cancel, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 4)
+            .ok_or("More than 4 CANCEL fields in the cancel format 1 packet")?;
+        tracer_cancel(&mut stream.tracer, cancel);
+        if (header & 1) != 0 {
+            tracer_mispredict(&mut stream.tracer);
+        }
+        Ok(1 + len)
+    } else if (header & 0xfc) == 0x34 {
+        /* cancel format 2: cancel one P0 element, followed by the atoms in AA */
+        tracer_cancel(&mut stream.tracer, 1);
+        decode_aa_atoms(stream, header & 0x03);
+        Ok(1)
+    } else {
+        /* cancel format 3: cancel CC + 2 P0 elements, followed by an E atom if A is set */
+        tracer_cancel(&mut stream.tracer, (((header & 0x06) >> 1) + 2) as u32);
+        if (header & 1) != 0 {
+            tracer_atom(&mut stream.tracer, AtomTypeE);
+        }
+        Ok(1)
+    }
 }
 
-pub fn decode_mispredict(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_mispredict(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_mispredict(&mut stream.tracer);
+    decode_aa_atoms(stream, stream.buff[pkt_offset] & 0x03);
+    Ok(1)
 }
 
-pub fn decode_cond_inst_format_1(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_cond_inst_format_1(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (key, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 5)
+        .ok_or("More than 5 KEY fields in the conditional instruction format 1 packet")?;
+
+    tracer_cond_inst(&mut stream.tracer, key);
+
+    Ok(1 + len)
 }
-pub fn decode_cond_inst_format_2(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_inst_format_2(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    /* CI selects how far the key of the C element is from the previous one */
+    let ci = (stream.buff[pkt_offset] & 0x03) as u32;
+    if stream.tracer.cond_key_max_incr != 0 && ci + 1 > stream.tracer.cond_key_max_incr {
+        return Err("Key increment of the conditional instruction format 2 packet is too large");
+    }
+    let key = stream.tracer.cond_c_key.wrapping_add(ci + 1);
+
+    tracer_cond_inst(&mut stream.tracer, key);
+
+    Ok(1)
 }
-pub fn decode_cond_inst_format_3(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_inst_format_3(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let data = *stream
+        .buff
+        .get(pkt_offset + 1)
+        .ok_or("Truncated conditional instruction format 3 packet")?;
+    /* bit 0 of the payload does not affect the keys of the C elements */
+    let num = (data & 0x7e) >> 1;
+
+    if num == 0 {
+        return Err("No C element in the conditional instruction format 3 packet");
+    }
+    /* the C elements have consecutive keys */
+    for _ in 0..num {
+        let key = stream.tracer.cond_c_key.wrapping_add(1);
+        tracer_cond_inst(&mut stream.tracer, key);
+    }
+
+    Ok(2)
 }
-pub fn decode_cond_flush(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_flush(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_cond_flush(&mut stream.tracer);
+    Ok(1)
 }
-pub fn decode_cond_result_format_1(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_result_format_1(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let mut index = 1;
+
+    /* U = 1'b1: one payload, whose CI bit is bit 0 of the header; otherwise two payloads */
+    let nr_payloads = if (header & 0x04) != 0 { 1 } else { 2 };
+
+    for payload in 0..nr_payloads {
+        let ci = (header >> payload) & 1;
+        let data = *stream
+            .buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated conditional result format 1 packet")?;
+        index += 1;
+
+        let result = (data & 0x0f) as u32;
+        let mut key = ((data & 0x70) >> 4) as u32;
+        if (data & C_BIT) != 0 {
+            let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 4)
+                .ok_or("More than 5 KEY fields in the conditional result format 1 packet")?;
+            key |= field << 3;
+            index += len;
+        }
+
+        if ci != 0 {
+            /* the R element also stands for a C element with the same key */
+            tracer_cond_inst(&mut stream.tracer, key);
+        }
+        tracer_cond_result(&mut stream.tracer, key, result);
+    }
+
+    Ok(index)
+}
+
+/* turn a result token of the conditional result formats 2 to 4 into an R element */
+fn decode_cond_result_token(stream: &mut Stream, key: u32, token: u8) -> Result<(), &'static str> {
+    if stream.tracer.condtype != CONDTYPE_PASS_FAIL {
+        return Err("Conditional result tokens are only used with CONDTYPE_PASS_FAIL");
+    }
+    match token {
+        1 => tracer_cond_result(&mut stream.tracer, key, 1),
+        2 => tracer_cond_result(&mut stream.tracer, key, 0),
+        _ => return Err("Invalid token in the conditional result packet"),
+    }
+    Ok(())
 }
-pub fn decode_cond_result_format_2(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_result_format_2(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let k = ((stream.buff[pkt_offset] & 0x04) >> 2) as u32;
+    let token = stream.buff[pkt_offset] & 0x03;
+    if stream.tracer.cond_key_max_incr != 0 && k + 1 > stream.tracer.cond_key_max_incr {
+        return Err("Key increment of the conditional result format 2 packet is too large");
+    }
+    let key = stream.tracer.cond_r_key.wrapping_add(k + 1);
+
+    decode_cond_result_token(stream, key, token)?;
+
+    Ok(1)
 }
-pub fn decode_cond_result_format_3(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_result_format_3(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let data = *stream
+        .buff
+        .get(pkt_offset + 1)
+        .ok_or("Truncated conditional result format 3 packet")?;
+    let tokens = (((stream.buff[pkt_offset] & 0x0f) as u16) << 8) | data as u16;
+
+    /* up to 6 tokens for consecutive keys, starting from the least significant bits */
+    for i in 0..6 {
+        let token = ((tokens >> (2 * i)) & 0x03) as u8;
+        if token == 0 {
+            break;
+        }
+        let key = stream.tracer.cond_r_key.wrapping_add(1);
+        decode_cond_result_token(stream, key, token)?;
+    }
+
+    Ok(2)
 }
-pub fn decode_cond_result_format_4(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+pub fn decode_cond_result_format_4(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let token = stream.buff[pkt_offset] & 0x03;
+    let key = stream.tracer.cond_r_key.wrapping_add(1);
+
+    decode_cond_result_token(stream, key, token)?;
+
+    Ok(1)
 }
-pub fn decode_event(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+
+/* ARMv8-M: a return to Non-secure state through FNC_RETURN */
+pub fn decode_function_return(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_function_return(&mut stream.tracer);
+    Ok(1)
+}
+
+pub fn decode_instrumentation(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    /* the EL byte is followed by the 64-bit operand of the TRCIT instruction */
+    let el = *stream
+        .buff
+        .get(pkt_offset + 1)
+        .ok_or("Truncated instrumentation packet")?;
+    if (el & 0xfc) != 0 {
+        return Err("Reserved bits set in the instrumentation packet");
+    }
+    let low = decode_sized_field(&stream.buff, pkt_offset + 2, 4)
+        .ok_or("Truncated instrumentation packet")?;
+    let high = decode_sized_field(&stream.buff, pkt_offset + 6, 4)
+        .ok_or("Truncated instrumentation packet")?;
+
+    tracer_instrumentation(&stream.tracer, el, ((high as u64) << 32) | low as u64);
+
+    Ok(10)
+}
+
+pub fn decode_transaction_start(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_transaction_start(&mut stream.tracer);
+    Ok(1)
+}
+
+pub fn decode_transaction_commit(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_transaction_commit(&mut stream.tracer);
+    Ok(1)
+}
+
+pub fn decode_timestamp_marker(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_timestamp_marker(&stream.tracer);
+    Ok(1)
+}
+
+pub fn decode_event(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    /* each of the EEEE bits in the header indicates that the corresponding event occurred */
+    tracer_event(&mut stream.tracer, stream.buff[pkt_offset] & 0x0f);
+    Ok(1)
 }
 
 fn update_address_regs(stream: &mut Stream, address: u64, is: AddrReg) {
@@ -360,12 +747,17 @@ fn update_address_regs(stream: &mut Stream, address: u64, is: AddrReg) {
     stream.tracer.address_register[0].is = is;
 }
 
-pub fn decode_short_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+/*
+ * Update the address registers from a short address, whose payload is also used by the Q
+ * and source address packets. header is the equivalent short address header.
+ * Return the length of the header and the address payload.
+ */
+fn decode_short_address_payload(pkt_offset: usize, stream: &mut Stream, header: u8) -> usize {
     let mut index = 1;
     let mut address = stream.tracer.address_register[0].address;
     let is;
 
-    if (stream.buff[pkt_offset] & 0x01) != 0 {
+    if (header & 0x01) != 0 {
         is = AddrRegIs0;
         address &= !0x000001FF;
         address |= ((stream.buff[pkt_offset + index] as u64) & 0x7F) << 2;
@@ -382,27 +774,40 @@ pub fn decode_short_address(pkt_offset: usize, stream: &mut Stream) -> Result<us
         index += 1;
         if (stream.buff[pkt_offset + 1] & C_BIT) != 0 {
             address &= !0x0000FF00;
-            address &= (stream.buff[pkt_offset + index] as u64) << 8;
+            address |= (stream.buff[pkt_offset + index] as u64) << 8;
             index += 1;
         }
     }
 
     update_address_regs(stream, address, is);
 
-    tracer_address(&stream.tracer);
+    index
+}
 
-    return Ok(index);
+pub fn decode_short_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let index = decode_short_address_payload(pkt_offset, stream, stream.buff[pkt_offset]);
+    tracer_address(&mut stream.tracer);
+    Ok(index)
 }
 
-pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+/*
+ * Update the address registers from a long address, whose payload is also used by the Q
+ * and source address packets. header is the equivalent long address header.
+ * Return the length of the header and the address payload.
+ */
+fn decode_long_address_payload(
+    pkt_offset: usize,
+    stream: &mut Stream,
+    header: u8,
+) -> Result<usize, &'static str> {
     let mut index = 1;
     let is;
     let mut address;
 
     address = stream.tracer.address_register[0].address;
 
-    match stream.buff[pkt_offset] {
-        0x9a => {
+    match header & 0x0f {
+        0x0a => {
             is = AddrRegIs0;
             address &= !0xFFFFFFFF;
             address |= ((stream.buff[pkt_offset + index] & 0x7F) as u64) << 2;
@@ -414,7 +819,7 @@ pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usi
             address |= (stream.buff[pkt_offset + index] as u64) << 24;
             index += 1;
         }
-        0x9b => {
+        0x0b => {
             is = AddrRegIs1;
             address &= !0xFFFFFFFF;
             address |= ((stream.buff[pkt_offset + index] & 0x7F) as u64) << 1;
@@ -426,7 +831,7 @@ pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usi
             address |= (stream.buff[pkt_offset + index] as u64) << 24;
             index += 1;
         }
-        0x9d => {
+        0x0d => {
             is = AddrRegIs0;
             address = 0;
             address |= ((stream.buff[pkt_offset + index] & 0x7F) as u64) << 2;
@@ -446,7 +851,7 @@ pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usi
             address |= (stream.buff[pkt_offset + index] as u64) << 56;
             index += 1;
         }
-        0x9e => {
+        0x0e => {
             is = AddrRegIs1;
             address = 0;
             address |= ((stream.buff[pkt_offset + index] as u64) & 0x7F) << 1;
@@ -467,13 +872,18 @@ pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usi
             index += 1;
         }
         _ => {
-            return Err("");
+            return Err("Invalid long address packet");
         }
     }
 
     update_address_regs(stream, address, is);
-    tracer_address(&stream.tracer);
-    return Ok(index);
+    Ok(index)
+}
+
+pub fn decode_long_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let index = decode_long_address_payload(pkt_offset, stream, stream.buff[pkt_offset])?;
+    tracer_address(&mut stream.tracer);
+    Ok(index)
 }
 
 pub fn decode_exact_match_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
@@ -483,15 +893,48 @@ pub fn decode_exact_match_address(pkt_offset: usize, stream: &mut Stream) -> Res
         stream.tracer.address_register[qe].address,
         stream.tracer.address_register[qe].is,
     );
-    tracer_address(&stream.tracer);
+    tracer_address(&mut stream.tracer);
     return Ok(1);
 }
 
+/*
+ * ETE source address packets carry the address of a taken branch, compressed against the same
+ * address registers as the target addresses.
+ */
+pub fn decode_source_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+
+    /* the target of the previous branch may be popped into the registers it is compressed with */
+    tracer_resolve_branch(&mut stream.tracer);
+    let index = match header {
+        0xb0..=0xb2 => {
+            let qe = (header & 0x03) as usize;
+            update_address_regs(
+                stream,
+                stream.tracer.address_register[qe].address,
+                stream.tracer.address_register[qe].is,
+            );
+            1
+        }
+        0xb4 => decode_short_address_payload(pkt_offset, stream, 0x95),
+        0xb5 => decode_short_address_payload(pkt_offset, stream, 0x96),
+        0xb6 => decode_long_address_payload(pkt_offset, stream, 0x9a)?,
+        0xb7 => decode_long_address_payload(pkt_offset, stream, 0x9b)?,
+        0xb8 => decode_long_address_payload(pkt_offset, stream, 0x9d)?,
+        0xb9 => decode_long_address_payload(pkt_offset, stream, 0x9e)?,
+        _ => return Err("Invalid source address packet"),
+    };
+
+    tracer_source_address(&mut stream.tracer);
+
+    Ok(index)
+}
+
 pub fn decode_context(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
     let mut index = 0;
     let mut el = 0;
     let mut sf = 0;
-    let mut ns = 0;
+    let mut security = NonSecure;
     let mut v = 0;
     let mut c = 0;
     let mut vmid = 0;
@@ -502,24 +945,25 @@ pub fn decode_context(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &
     if (data & 1) != 0 {
         data = stream.buff[pkt_offset + index];
         index += 1;
+        check_context_info(data, stream)?;
         el = data & 0x3;
         sf = (data & 0x10) >> 4;
-        ns = (data & 0x20) >> 5;
+        security = SecurityState::from_nse_ns((data & 0x08) >> 3, (data & 0x20) >> 5);
         if (data & 0x40) != 0 {
             v = 1;
-            vmid = stream.buff[pkt_offset + index];
-            index += 1;
+            vmid = decode_sized_field(&stream.buff, pkt_offset + index, stream.tracer.vmid_size)
+                .ok_or("Truncated VMID in the context packet")?;
+            index += stream.tracer.vmid_size as usize;
         }
         if (data & 0x80) != 0 {
             c = 1;
-            contextid = stream.buff[pkt_offset + index] as u32;
-            index += 1;
-            contextid |= (stream.buff[pkt_offset + index] as u32) << 8;
-            index += 1;
-            contextid |= (stream.buff[pkt_offset + index] as u32) << 16;
-            index += 1;
-            contextid |= (stream.buff[pkt_offset + index] as u32) << 24;
-            index += 1;
+            contextid = decode_sized_field(
+                &stream.buff,
+                pkt_offset + index,
+                stream.tracer.context_id_size,
+            )
+            .ok_or("Truncated context ID in the context packet")?;
+            index += stream.tracer.context_id_size as usize;
         }
     }
 
@@ -528,7 +972,7 @@ pub fn decode_context(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &
         stream.buff[pkt_offset] & 1,
         el,
         sf,
-        ns,
+        security,
         v,
         vmid,
         c,
@@ -543,7 +987,6 @@ pub fn decode_address_context(pkt_offset: usize, stream: &mut Stream) -> Result<
     let is;
     let el;
     let sf;
-    let ns;
     let mut v = 0;
     let mut c = 0;
     let data;
@@ -625,28 +1068,39 @@ pub fn decode_address_context(pkt_offset: usize, stream: &mut Stream) -> Result<
 
     data = stream.buff[pkt_offset + index];
     index += 1;
+    check_context_info(data, stream)?;
     el = data & 0x3;
     sf = (data & 0x10) >> 4;
-    ns = (data & 0x20) >> 5;
+    let security = SecurityState::from_nse_ns((data & 0x08) >> 3, (data & 0x20) >> 5);
     if (data & 0x40) != 0 {
         v = 1;
-        vmid = stream.buff[pkt_offset + index];
-        index += 1;
+        vmid = decode_sized_field(&stream.buff, pkt_offset + index, stream.tracer.vmid_size)
+            .ok_or("Truncated VMID in the address with context packet")?;
+        index += stream.tracer.vmid_size as usize;
     }
     if (data & 0x80) != 0 {
         c = 1;
-        contextid = stream.buff[pkt_offset + index] as u32;
-        index += 1;
-        contextid |= (stream.buff[pkt_offset + index] as u32) << 8;
-        index += 1;
-        contextid |= (stream.buff[pkt_offset + index] as u32) << 16;
-        index += 1;
-        contextid |= (stream.buff[pkt_offset + index] as u32) << 24;
-        index += 1;
+        contextid = decode_sized_field(
+            &stream.buff,
+            pkt_offset + index,
+            stream.tracer.context_id_size,
+        )
+        .ok_or("Truncated context ID in the address with context packet")?;
+        index += stream.tracer.context_id_size as usize;
     }
 
-    tracer_context(&mut stream.tracer, 1, el, sf, ns, v, vmid, c, contextid);
-    tracer_address(&stream.tracer);
+    tracer_context(
+        &mut stream.tracer,
+        1,
+        el,
+        sf,
+        security,
+        v,
+        vmid,
+        c,
+        contextid,
+    );
+    tracer_address(&mut stream.tracer);
 
     return Ok(index);
 }
@@ -762,33 +1216,90 @@ pub fn decode_atom_format_5(pkt_offset: usize, stream: &mut Stream) -> Result<us
     return Ok(1);
 }
 
-pub fn decode_atom_format_6(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_atom_format_6(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let a = stream.buff[pkt_offset] & 0x20;
+    let count = stream.buff[pkt_offset] & 0x1f;
+
+    /* COUNT + 3 E atoms, followed by an N atom if A is 1'b1 or by another E atom if not */
+    for _ in 0..(count + 3) {
+        tracer_atom(&mut stream.tracer, AtomTypeE);
+    }
+    tracer_atom(
+        &mut stream.tracer,
+        if a != 0 { AtomTypeN } else { AtomTypeE },
+    );
+
+    Ok(1)
 }
 
-pub fn decode_q(_: usize, _: &mut Stream) -> Result<usize, &str> {
-    unimplemented!()
+pub fn decode_q(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let mut index = 1;
+    let mut count = None;
+    let qtype = stream.buff[pkt_offset] & 0x0f;
+
+    /* the address section is not the target of the previous branch */
+    tracer_resolve_branch(&mut stream.tracer);
+    /* the address section of a Q packet is encoded like the corresponding address packet */
+    let has_address = match qtype {
+        0x0..=0x2 => {
+            let qe = qtype as usize;
+            update_address_regs(
+                stream,
+                stream.tracer.address_register[qe].address,
+                stream.tracer.address_register[qe].is,
+            );
+            true
+        }
+        0x5 | 0x6 => {
+            index = decode_short_address_payload(pkt_offset, stream, stream.buff[pkt_offset]);
+            true
+        }
+        0xa | 0xb => {
+            index = decode_long_address_payload(pkt_offset, stream, stream.buff[pkt_offset])?;
+            true
+        }
+        0xc | 0xf => false,
+        _ => {
+            return Err("Invalid type of the Q packet");
+        }
+    };
+
+    if qtype != 0xf {
+        /* the COUNT section is present unless the instruction count is unknown */
+        let (field, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 5)
+            .ok_or("More than 5 COUNT fields in the Q packet")?;
+        count = Some(field);
+        index += len;
+    }
+
+    tracer_q(&mut stream.tracer, count, has_address);
+
+    Ok(index)
 }
 
-pub const TRACEPKTS: [TracePkt; 56] = [
+pub const TRACEPKTS: [TracePkt; 62] = [
     TracePkt::new("extension", 0xff, 0x00),
     TracePkt::new("trace_info", 0xff, 0x01),
     TracePkt::new("trace_on", 0xff, 0x04),
     TracePkt::new("timestamp", 0xfe, 0x02),
     TracePkt::new("exception", 0xfe, 0x06),
+    TracePkt::new("function_return", 0xff, 0x05),
+    TracePkt::new("instrumentation", 0xff, 0x09),
+    TracePkt::new("transaction_start", 0xff, 0x0a),
+    TracePkt::new("transaction_commit", 0xff, 0x0b),
     TracePkt::new("cc_format_1", 0xfe, 0x0e),
     TracePkt::new("cc_format_2", 0xfe, 0x0c),
     TracePkt::new("cc_format_3", 0xf0, 0x10),
-    TracePkt::new("data_sync_marker", 0xf0, 0x20),
     TracePkt::new("commit", 0xff, 0x2d),
     TracePkt::new("cancel_format_1", 0xfe, 0x2e),
+    TracePkt::new("data_sync_marker", 0xf0, 0x20),
     TracePkt::new("cancel_format_2", 0xfc, 0x34),
     TracePkt::new("cancel_format_3", 0xf8, 0x38),
     TracePkt::new("mispredict", 0xfc, 0x30),
     TracePkt::new("cond_inst_format_1", 0xff, 0x6c),
+    TracePkt::new("cond_flush", 0xff, 0x43),
     TracePkt::new("cond_inst_format_2", 0xfc, 0x40),
     TracePkt::new("cond_inst_format_3", 0xff, 0x6d),
-    TracePkt::new("cond_flush", 0xff, 0x43),
     TracePkt::new("cond_result_format_1", 0xf8, 0x68),
     TracePkt::new("cond_result_format_2", 0xf8, 0x48),
     TracePkt::new("cond_result_format_3", 0xf0, 0x50),
@@ -802,6 +1313,7 @@ pub const TRACEPKTS: [TracePkt; 56] = [
     TracePkt::new("long_address_64bit_is1", 0xff, 0x9e),
     TracePkt::new("exact_match_address", 0xfc, 0x90),
     TracePkt::new("context", 0xfe, 0x80),
+    TracePkt::new("timestamp_marker", 0xff, 0x88),
     TracePkt::new("address_context_32bit_is0", 0xff, 0x82),
     TracePkt::new("address_context_32bit_is1", 0xff, 0x83),
     TracePkt::new("address_context_64bit_is0", 0xff, 0x85),
@@ -827,6 +1339,7 @@ pub const TRACEPKTS: [TracePkt; 56] = [
     TracePkt::new("atom_format_6_11", 0xf0, 0xc0),
     TracePkt::new("atom_format_6_12", 0xf0, 0xe0),
     TracePkt::new("q", 0xf0, 0xa0),
+    TracePkt::new("source_address", 0xf0, 0xb0),
 ];
 
 const EXTENSION_PACKET_MASK: u8 = 0xff;
@@ -834,6 +1347,17 @@ const EXTENSION_PACKET_VAL: u8 = 0x00;
 const TRACE_INFO_PACKET_MASK: u8 = 0xff;
 const TRACE_INFO_PACKET_VAL: u8 = 0x01;
 
+/* find the next A-sync packet, 11 bytes of 0x00 followed by 0x80, at or after from */
+pub fn etmv4_find_async(buff: &[u8], from: usize) -> Option<usize> {
+    if from >= buff.len() {
+        return None;
+    }
+    buff[from..]
+        .windows(12)
+        .position(|w| w[..11].iter().all(|&b| b == 0) && w[11] == 0x80)
+        .map(|i| from + i)
+}
+
 pub fn etmv4_synchronization(stream: &mut Stream) -> Result<usize, &str> {
     let mut c: u8;
     for i in 0..stream.buff.len() {
@@ -874,3 +1398,518 @@ pub fn etmv4_synchronization(stream: &mut Stream) -> Result<usize, &str> {
      */
     return Err("No trace info packet right after an a-sync packet");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* decode the packet at the start of buff, checking that it is the named one */
+    fn decode(stream: &mut Stream, buff: &[u8], name: &str) -> Result<usize, String> {
+        stream.buff = buff.to_vec();
+        let pkt = TRACEPKTS
+            .iter()
+            .find(|pkt| (buff[0] & pkt.mask) == pkt.val)
+            .unwrap();
+        assert_eq!(pkt.name, name);
+        get_decode_func(pkt.name).unwrap()(0, stream).map_err(String::from)
+    }
+
+    /* a stream with nr speculative E atoms, which stay speculative until they are committed */
+    fn speculative_atoms(nr: usize) -> Stream {
+        let mut stream = Stream::new();
+        stream.tracer.max_spec_depth = 16;
+        for _ in 0..nr {
+            tracer_atom(&mut stream.tracer, AtomTypeE);
+        }
+        stream
+    }
+
+    /* the speculative atoms, E as true */
+    fn atoms(stream: &Stream) -> Vec<bool> {
+        stream
+            .tracer
+            .spec_queue
+            .iter()
+            .map(|elem| match elem {
+                P0Element::P0Atom(AtomTypeE) => true,
+                P0Element::P0Atom(AtomTypeN) => false,
+                _ => panic!("not an atom"),
+            })
+            .collect()
+    }
+
+    /* decode a single header and return the atoms it queued */
+    fn decode_atoms(header: u8) -> Vec<bool> {
+        let mut stream = Stream::new();
+        stream.buff = vec![header];
+        /* keep the atoms speculative, format 6 has up to 35 of them */
+        stream.tracer.max_spec_depth = 64;
+
+        let pkt = TRACEPKTS
+            .iter()
+            .find(|pkt| (header & pkt.mask) == pkt.val)
+            .unwrap();
+        assert!(pkt.name.starts_with("atom_format_6"), "0x{:02x}", header);
+        let len = get_decode_func(pkt.name).unwrap()(0, &mut stream).unwrap();
+        assert_eq!(len, 1);
+
+        atoms(&stream)
+    }
+
+    #[test]
+    fn atom_format_6() {
+        for header in (0xc0..=0xd4).chain(0xe0..=0xf4) {
+            let count = (header & 0x1f) as usize;
+            let mut expected = vec![true; count + 3];
+            /* A is bit[5], the last atom is N if it is set */
+            expected.push((header & 0x20) == 0);
+
+            assert_eq!(decode_atoms(header), expected, "0x{:02x}", header);
+        }
+    }
+    #[test]
+    fn packets_of_the_version() {
+        let mut stream = Stream::new();
+        stream.tracer.m_profile = true;
+
+        stream.etmv4_version = 1;
+        assert!(!etmv4_packet_in_version("function_return", &stream));
+        assert!(etmv4_packet_in_version("atom_format_1", &stream));
+        stream.etmv4_version = 2;
+        assert!(etmv4_packet_in_version("function_return", &stream));
+        assert!(etmv4_packet_supported("function_return", &stream));
+
+        stream.tracer.m_profile = false;
+        assert!(!etmv4_packet_supported("function_return", &stream));
+    }
+    #[test]
+    fn cc_format_1() {
+        /* COMMIT of 2 and a COUNT of 0x85 in 2 bytes */
+        let mut stream = speculative_atoms(3);
+        stream.tracer.nr_p0_since_cc = 1;
+        assert_eq!(
+            decode(&mut stream, &[0x0e, 0x02, 0x85, 0x01], "cc_format_1"),
+            Ok(4)
+        );
+        assert_eq!(spec_depth(&stream.tracer), 1);
+        assert_eq!(stream.tracer.nr_p0_since_cc, 0);
+
+        /* with TRCIDR0.COMMOPT set there is no COMMIT section, and U leaves out the COUNT */
+        let mut stream = speculative_atoms(3);
+        stream.tracer.commopt = 1;
+        assert_eq!(decode(&mut stream, &[0x0f], "cc_format_1"), Ok(1));
+        assert_eq!(spec_depth(&stream.tracer), 3);
+
+        let mut stream = Stream::new();
+        assert!(decode(&mut stream, &[0x0e, 0x80, 0x80, 0x80, 0x80], "cc_format_1").is_err());
+    }
+    #[test]
+    fn cc_format_2() {
+        /* AAAA + 1 P0 elements are committed */
+        let mut stream = speculative_atoms(5);
+        assert_eq!(decode(&mut stream, &[0x0c, 0x23], "cc_format_2"), Ok(2));
+        assert_eq!(spec_depth(&stream.tracer), 2);
+
+        /* with F set the commit count is max_spec_depth + AAAA - 15 */
+        let mut stream = speculative_atoms(5);
+        assert_eq!(decode(&mut stream, &[0x0d, 0x21], "cc_format_2"), Ok(2));
+        assert_eq!(spec_depth(&stream.tracer), 2);
+
+        /* which must not be negative */
+        let mut stream = speculative_atoms(5);
+        stream.tracer.max_spec_depth = 8;
+        assert!(decode(&mut stream, &[0x0d, 0x61], "cc_format_2").is_err());
+        assert!(decode(&mut stream, &[0x0c], "cc_format_2").is_err());
+    }
+    #[test]
+    fn cc_format_3() {
+        /* AA + 1 P0 elements are committed */
+        let mut stream = speculative_atoms(5);
+        assert_eq!(decode(&mut stream, &[0x1a], "cc_format_3"), Ok(1));
+        assert_eq!(spec_depth(&stream.tracer), 2);
+        assert_eq!(stream.tracer.nr_p0_since_cc, 0);
+
+        /* with TRCIDR0.COMMOPT set the packet commits nothing */
+        let mut stream = speculative_atoms(5);
+        stream.tracer.commopt = 1;
+        assert_eq!(decode(&mut stream, &[0x1f], "cc_format_3"), Ok(1));
+        assert_eq!(spec_depth(&stream.tracer), 5);
+    }
+    #[test]
+    fn commit() {
+        let mut stream = speculative_atoms(4);
+        assert_eq!(decode(&mut stream, &[0x2d, 0x83, 0x00], "commit"), Ok(3));
+        assert_eq!(spec_depth(&stream.tracer), 1);
+
+        assert!(decode(&mut stream, &[0x2d, 0x80, 0x80, 0x80, 0x80], "commit").is_err());
+    }
+    #[test]
+    fn cancel() {
+        /* format 1: CANCEL P0 elements, and a mispredict if M is set */
+        let mut stream = speculative_atoms(4);
+        assert_eq!(decode(&mut stream, &[0x2e, 0x02], "cancel_format_1"), Ok(2));
+        assert_eq!(atoms(&stream), [true, true]);
+        assert_eq!(decode(&mut stream, &[0x2f, 0x01], "cancel_format_1"), Ok(2));
+        assert_eq!(atoms(&stream), [false]);
+
+        /* format 2: one P0 element, then the atoms in AA */
+        let mut stream = speculative_atoms(4);
+        assert_eq!(decode(&mut stream, &[0x36], "cancel_format_2"), Ok(1));
+        assert_eq!(atoms(&stream), [true, true, true, true, true]);
+        assert_eq!(decode(&mut stream, &[0x37], "cancel_format_2"), Ok(1));
+        assert_eq!(atoms(&stream), [true, true, true, true, false]);
+
+        /* format 3: CC + 2 P0 elements, then an E atom if A is set */
+        let mut stream = speculative_atoms(4);
+        assert_eq!(decode(&mut stream, &[0x3b], "cancel_format_3"), Ok(1));
+        assert_eq!(atoms(&stream), [true, true]);
+        assert_eq!(decode(&mut stream, &[0x38], "cancel_format_3"), Ok(1));
+        assert!(atoms(&stream).is_empty());
+    }
+    #[test]
+    fn mispredict() {
+        /* the most recent atom is inverted before the atoms in AA are added */
+        let mut stream = speculative_atoms(2);
+        assert_eq!(decode(&mut stream, &[0x33], "mispredict"), Ok(1));
+        assert_eq!(atoms(&stream), [true, false, false]);
+        assert_eq!(decode(&mut stream, &[0x30], "mispredict"), Ok(1));
+        assert_eq!(atoms(&stream), [true, false, true]);
+    }
+    #[test]
+    fn cond_inst() {
+        let mut stream = Stream::new();
+        assert_eq!(
+            decode(&mut stream, &[0x6c, 0x85, 0x01], "cond_inst_format_1"),
+            Ok(3)
+        );
+        assert_eq!(stream.tracer.cond_c_key, 0x85);
+
+        /* format 2: the key is CI + 1 after the previous one */
+        assert_eq!(decode(&mut stream, &[0x41], "cond_inst_format_2"), Ok(1));
+        assert_eq!(stream.tracer.cond_c_key, 0x87);
+
+        /* format 3: NUM C elements with consecutive keys */
+        assert_eq!(
+            decode(&mut stream, &[0x6d, 0x07], "cond_inst_format_3"),
+            Ok(2)
+        );
+        assert_eq!(stream.tracer.cond_queue, [0x85, 0x87, 0x88, 0x89, 0x8a]);
+        assert!(decode(&mut stream, &[0x6d, 0x01], "cond_inst_format_3").is_err());
+
+        stream.tracer.cond_key_max_incr = 1;
+        assert!(decode(&mut stream, &[0x41], "cond_inst_format_2").is_err());
+
+        assert_eq!(decode(&mut stream, &[0x43], "cond_flush"), Ok(1));
+        assert!(stream.tracer.cond_queue.is_empty());
+    }
+    #[test]
+    fn cond_result_format_1() {
+        let mut stream = Stream::new();
+        tracer_cond_inst(&mut stream.tracer, 3);
+        tracer_cond_inst(&mut stream.tracer, 0xb);
+
+        /* U is set, a single payload with a key of 3 */
+        assert_eq!(
+            decode(&mut stream, &[0x6e, 0x31], "cond_result_format_1"),
+            Ok(2)
+        );
+        assert_eq!(stream.tracer.cond_queue, [0xb]);
+
+        /* two payloads, the first one with a key of 0xb in 2 bytes and CI set */
+        assert_eq!(
+            decode(
+                &mut stream,
+                &[0x69, 0xb1, 0x01, 0x40],
+                "cond_result_format_1"
+            ),
+            Ok(4)
+        );
+        assert_eq!(stream.tracer.cond_r_key, 4);
+        assert_eq!(stream.tracer.cond_queue, [0xb]);
+
+        assert!(decode(&mut stream, &[0x68, 0x31], "cond_result_format_1").is_err());
+    }
+    #[test]
+    fn cond_result_tokens() {
+        let mut stream = Stream::new();
+
+        /* format 2: the key is K + 1 after the previous one */
+        assert_eq!(decode(&mut stream, &[0x4d], "cond_result_format_2"), Ok(1));
+        assert_eq!(stream.tracer.cond_r_key, 2);
+
+        /* format 3: the tokens up to the first zero one */
+        assert_eq!(
+            decode(&mut stream, &[0x50, 0x1a], "cond_result_format_3"),
+            Ok(2)
+        );
+        assert_eq!(stream.tracer.cond_r_key, 5);
+
+        assert_eq!(decode(&mut stream, &[0x46], "cond_result_format_4"), Ok(1));
+        assert_eq!(stream.tracer.cond_r_key, 6);
+        assert!(decode(&mut stream, &[0x47], "cond_result_format_4").is_err());
+
+        /* the tokens only tell whether the instruction passed */
+        stream.tracer.condtype = CONDTYPE_APSR;
+        assert!(decode(&mut stream, &[0x45], "cond_result_format_4").is_err());
+    }
+    #[test]
+    fn event() {
+        /* the events wait for the speculative atom, in the order of the EEEE bits */
+        let mut stream = speculative_atoms(1);
+        stream.tracer.event_names[2] = Some(String::from("dma"));
+        assert_eq!(decode(&mut stream, &[0x75], "event"), Ok(1));
+
+        let events: Vec<&str> = stream
+            .tracer
+            .spec_queue
+            .iter()
+            .filter_map(|elem| match elem {
+                P0Element::P0Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events, ["Event - event 0", "Event - event 2 (dma)"]);
+    }
+    /* decode a Q packet and return its instruction count and address */
+    fn decode_q_packet(stream: &mut Stream, buff: &[u8]) -> (usize, Option<u32>, Option<u64>) {
+        let len = decode(stream, buff, "q").unwrap();
+        match stream.tracer.spec_queue.back() {
+            Some(P0Element::P0Q { count, address }) => {
+                (len, *count, address.map(|reg| reg.address))
+            }
+            _ => panic!("not a Q element"),
+        }
+    }
+
+    #[test]
+    fn q() {
+        let mut stream = speculative_atoms(0);
+        stream.tracer.address_register[1].address = 0x1000;
+
+        assert_eq!(
+            decode_q_packet(&mut stream, &[0xac, 0x05]),
+            (2, Some(5), None)
+        );
+        assert_eq!(decode_q_packet(&mut stream, &[0xaf]), (1, None, None));
+
+        /* the address is compressed like the one of the address packets */
+        assert_eq!(
+            decode_q_packet(&mut stream, &[0xa1, 0x03]),
+            (2, Some(3), Some(0x1000))
+        );
+        assert_eq!(
+            decode_q_packet(&mut stream, &[0xa5, 0x10, 0x85, 0x01]),
+            (4, Some(0x85), Some(0x1040))
+        );
+        assert_eq!(
+            decode_q_packet(&mut stream, &[0xaa, 0x04, 0x00, 0x02, 0x00, 0x01]),
+            (6, Some(1), Some(0x20010))
+        );
+        assert_eq!(stream.tracer.address_register[1].address, 0x1040);
+
+        assert!(decode(&mut stream, &[0xa3], "q").is_err());
+    }
+    #[test]
+    fn data_sync_marker() {
+        let mut stream = Stream::new();
+        assert_eq!(decode(&mut stream, &[0x2a], "data_sync_marker"), Ok(1));
+        assert_eq!(stream.tracer.data_sync_number, None);
+        assert_eq!(stream.tracer.nr_unnumbered_markers, 1);
+
+        /* the unnumbered markers are counted from the most recent numbered one */
+        assert_eq!(decode(&mut stream, &[0x23], "data_sync_marker"), Ok(1));
+        assert_eq!(stream.tracer.data_sync_number, Some(3));
+        assert_eq!(stream.tracer.nr_unnumbered_markers, 0);
+        assert_eq!(decode(&mut stream, &[0x2c], "data_sync_marker"), Ok(1));
+        assert_eq!(decode(&mut stream, &[0x28], "data_sync_marker"), Ok(1));
+        assert_eq!(stream.tracer.nr_unnumbered_markers, 2);
+    }
+    #[test]
+    fn discard_and_overflow() {
+        let mut stream = speculative_atoms(3);
+        tracer_cond_inst(&mut stream.tracer, 1);
+
+        /* nothing is canceled before the trace stream is synchronized */
+        stream.state = State::Syncing;
+        assert_eq!(decode(&mut stream, &[0x00, 0x03], "extension"), Ok(2));
+        assert_eq!(spec_depth(&stream.tracer), 3);
+
+        stream.state = State::InSync;
+        assert_eq!(decode(&mut stream, &[0x00, 0x03], "extension"), Ok(2));
+        assert!(stream.tracer.spec_queue.is_empty());
+        assert!(stream.tracer.cond_queue.is_empty());
+        assert!(stream.state == State::InSync);
+
+        /* an overflow also loses the addresses, and waits for the next TraceInfo */
+        let mut stream = speculative_atoms(3);
+        stream.state = State::InSync;
+        stream.tracer.address_register[0].address = 0x1000;
+        assert_eq!(decode(&mut stream, &[0x00, 0x05], "extension"), Ok(2));
+        assert!(stream.tracer.spec_queue.is_empty());
+        assert_eq!(stream.tracer.address_register[0].address, 0);
+        assert!(stream.state == State::Syncing);
+
+        assert!(decode(&mut stream, &[0x00, 0x04], "extension").is_err());
+    }
+    #[test]
+    fn context_vmid_sizes() {
+        let mut stream = Stream::new();
+        stream.tracer.vmid_size = 2;
+
+        /* EL1, AArch64, Secure, with a 16-bit VMID and a 32-bit context ID */
+        let buff = [0x81, 0xd1, 0x34, 0x12, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(decode(&mut stream, &buff, "context"), Ok(8));
+        assert_eq!(stream.tracer.vmid, 0x1234);
+        assert_eq!(stream.tracer.context_id, 0x12345678);
+        assert_eq!(stream.tracer.ex_level, 1);
+        assert!(stream.tracer.sixty_four_bit);
+        assert!(stream.tracer.security == Secure);
+
+        assert!(decode(&mut stream, &[0x81, 0x40, 0x34], "context").is_err());
+        assert_eq!(decode(&mut stream, &[0x80], "context"), Ok(1));
+        assert_eq!(stream.tracer.vmid, 0x1234);
+
+        /* with VMIDOPT the VMID field is CONTEXTIDR_EL2, which needs ETMv4.1 */
+        stream.tracer.vmid_size = 4;
+        stream.tracer.vmidopt = true;
+        let buff = [0x81, 0x60, 0x44, 0x33, 0x22, 0x11];
+        assert_eq!(decode(&mut stream, &buff, "context"), Ok(6));
+        assert_eq!(stream.tracer.context_id_el2, 0x11223344);
+        assert_eq!(stream.tracer.vmid, 0x1234);
+        assert!(stream.tracer.security == NonSecure);
+
+        assert!(etmv4_check_version(&stream).is_ok());
+        stream.etmv4_version = 0;
+        assert!(etmv4_check_version(&stream).is_err());
+    }
+    #[test]
+    fn return_before_source_address() {
+        let mut stream = Stream::new();
+        tracer_enable_ete(&mut stream.tracer).unwrap();
+        /* branch broadcast and the return stack */
+        let config = Etmv4Config {
+            trcconfigr: (1 << 12) | (1 << 3),
+            ..stream.tracer.config
+        };
+        tracer_configure(&mut stream.tracer, &config).unwrap();
+        stream.tracer.sixty_four_bit = true;
+        stream.tracer.link_branches.push((0x1000, 4));
+
+        /* a branch with link at 0x1000 to 0x2000 */
+        let buff = [0xb8, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(decode(&mut stream, &buff, "source_address"), Ok(9));
+        assert_eq!(
+            decode(&mut stream, &[0x95, 0x80, 0x10], "short_address_is0"),
+            Ok(3)
+        );
+        /* a return at 0x2010, whose target is not traced */
+        assert_eq!(decode(&mut stream, &[0xb4, 0x04], "source_address"), Ok(2));
+        assert_eq!(stream.tracer.nr_branch_records, 1);
+
+        /*
+         * The target of the return is popped before the next source address, which is
+         * compressed against it, and the return gets it as its branch record.
+         */
+        assert_eq!(decode(&mut stream, &[0xb4, 0x02], "source_address"), Ok(2));
+        assert_eq!(stream.tracer.nr_branch_records, 2);
+        assert!(stream.tracer.return_stack.is_empty());
+        assert_eq!(stream.tracer.address_register[0].address, 0x1008);
+        assert_eq!(stream.tracer.address_register[1].address, 0x1004);
+        assert_eq!(stream.tracer.pending_source, Some(0x1008));
+
+        assert_eq!(
+            decode(&mut stream, &[0x95, 0x40], "short_address_is0"),
+            Ok(2)
+        );
+        assert_eq!(stream.tracer.nr_branch_records, 3);
+        assert_eq!(stream.tracer.address_register[0].address, 0x1100);
+        assert_eq!(stream.tracer.address_register[1].address, 0x1008);
+        assert_eq!(stream.tracer.nr_unresolved_branches, 0);
+    }
+    #[test]
+    fn reserved_headers_of_each_version() {
+        let mut stream = Stream::new();
+        stream.tracer.m_profile = true;
+
+        for version in 0..=ETMV4_LATEST_VERSION {
+            stream.etmv4_version = version;
+            /* the headers added after ETMv4.0 are reserved before their version */
+            for (name, min_version) in ETMV4_MIN_VERSION_PKTS {
+                let pkt = TRACEPKTS.iter().find(|pkt| pkt.name == name).unwrap();
+                assert_eq!(
+                    etmv4_find_packet(pkt.val, &stream).is_some(),
+                    version >= min_version,
+                    "{} in ETMv4.{}",
+                    name,
+                    version
+                );
+            }
+            /* the headers ETE added are reserved in every ETMv4 version */
+            for header in [0x09, 0x0a, 0x0b, 0x88, 0xb0] {
+                assert!(etmv4_find_packet(header, &stream).is_none());
+            }
+            assert!(etmv4_find_packet(0xf6, &stream).is_some());
+        }
+
+        stream.tracer.m_profile = false;
+        tracer_enable_ete(&mut stream.tracer).unwrap();
+        for header in [0x09, 0x0a, 0x0b, 0x88, 0xb0] {
+            assert!(etmv4_find_packet(header, &stream).is_some());
+        }
+    }
+    #[test]
+    fn ete_packets() {
+        let mut stream = Stream::new();
+        tracer_enable_ete(&mut stream.tracer).unwrap();
+        stream.state = State::InSync;
+
+        let buff = [0x09, 0x01, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x80];
+        assert_eq!(decode(&mut stream, &buff, "instrumentation"), Ok(10));
+        assert!(decode(
+            &mut stream,
+            &[0x09, 0x04, 0, 0, 0, 0, 0, 0, 0, 0],
+            "instrumentation"
+        )
+        .is_err());
+        assert!(decode(&mut stream, &buff[..9], "instrumentation").is_err());
+        assert_eq!(decode(&mut stream, &[0x88], "timestamp_marker"), Ok(1));
+
+        /* the INFO section of TraceInfo takes 2 bytes, bit[6] is the Transactional state */
+        let buff = [0x01, 0x01, 0xc0, 0x00];
+        assert_eq!(decode(&mut stream, &buff, "trace_info"), Ok(4));
+        assert_eq!(stream.tracer.info, 0x40);
+        assert_eq!(stream.tracer.transaction_depth, 1);
+        assert!(stream.tracer.transaction_start_unknown);
+
+        /* EL3 is always Secure */
+        assert!(decode(&mut stream, &[0x81, 0x23], "context").is_err());
+        assert_eq!(decode(&mut stream, &[0x81, 0x03], "context"), Ok(2));
+        assert_eq!(stream.tracer.ex_level, 3);
+
+        /* an ETMv4 only has one INFO byte */
+        let mut stream = Stream::new();
+        stream.state = State::InSync;
+        assert!(decode(&mut stream, &[0x01, 0x01, 0xc0, 0x00], "trace_info").is_err());
+    }
+    #[test]
+    fn rme_security_states() {
+        let mut stream = Stream::new();
+        tracer_enable_ete(&mut stream.tracer).unwrap();
+
+        /* NSE and NS: EL3 in Root state, EL1 in Realm state */
+        assert_eq!(decode(&mut stream, &[0x81, 0x0b], "context"), Ok(2));
+        assert!(stream.tracer.security == Root);
+        assert_eq!(decode(&mut stream, &[0x81, 0x29], "context"), Ok(2));
+        assert!(stream.tracer.security == Realm);
+        assert_eq!(stream.tracer.ex_level, 1);
+
+        /* only EL3 is in Root state, and EL3 is never in Realm state */
+        assert!(decode(&mut stream, &[0x81, 0x09], "context").is_err());
+        assert!(decode(&mut stream, &[0x81, 0x2b], "context").is_err());
+        assert!(stream.tracer.security == Realm);
+
+        /* NSE is reserved in ETMv4 */
+        let mut stream = Stream::new();
+        assert!(decode(&mut stream, &[0x81, 0x29], "context").is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use crate::pktproto::{BitDecodeFunc, BitReader, DecodeFunc, TracePkt};
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_etrace::*;
+
+/*
+ * RISC-V E-Trace te_inst packets are bit-oriented. Each one is sent in an encapsulation packet:
+ * a header byte with the number of payload bytes in bits[4:0], the flow indicator in bits[6:5]
+ * and the extend bit in bit[7], followed by the payload. The source ID and the timestamp of the
+ * encapsulation are not configured, so they are not present.
+ */
+
+const LENGTH_MASK: u8 = 0x1f;
+
+pub fn get_etrace_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "null" => Some(decode_etrace_null),
+        "packet" => Some(decode_etrace_packet),
+        _ => None,
+    }
+}
+
+fn get_etrace_payload_func(pkt_name: &str) -> Option<BitDecodeFunc> {
+    match pkt_name {
+        "branch_count" => Some(decode_etrace_branch_count),
+        "jump_target_index" => Some(decode_etrace_jump_target_index),
+        "branch" => Some(decode_etrace_branch),
+        "address" => Some(decode_etrace_address),
+        "sync_start" => Some(decode_etrace_sync_start),
+        "sync_trap" => Some(decode_etrace_sync_trap),
+        "sync_context" => Some(decode_etrace_sync_context),
+        "sync_support" => Some(decode_etrace_sync_support),
+        _ => None,
+    }
+}
+
+/* the size of the branch map for the number of branches, 31 bits when the map is full */
+fn branch_map_size(nr_branches: u32) -> u32 {
+    match nr_branches {
+        0 => 31,
+        1 => 1,
+        2..=3 => 3,
+        4..=7 => 7,
+        8..=15 => 15,
+        _ => 31,
+    }
+}
+
+fn read_address(reader: &mut BitReader, stream: &Stream) -> u64 {
+    let config = &stream.etrace_tracer.config;
+    reader.read(config.iaddress_width - config.iaddress_lsb)
+}
+
+/* the width of irdepth, which holds a return stack depth and a nested call count */
+fn irdepth_width(stream: &Stream) -> u32 {
+    let config = &stream.etrace_tracer.config;
+    config.return_stack_size + (config.return_stack_size > 0) as u32 + config.call_counter_size
+}
+
+/*
+ * Read the address of the format 0 to 2 packets and the notify, updiscon, irreport and irdepth
+ * fields after it. Each flag is set when its bit differs from the bit before it, so a packet cut
+ * short after its address still reads as no notification, no discontinuity and no depth.
+ */
+fn read_address_flags(reader: &mut BitReader, stream: &Stream) -> (u64, bool, bool, Option<u32>) {
+    let config = &stream.etrace_tracer.config;
+    let address = read_address(reader, stream);
+    let msb = (address >> (config.iaddress_width - config.iaddress_lsb - 1)) & 1;
+    let notify = reader.read(1);
+    let updiscon = reader.read(1);
+    let irreport = reader.read(1);
+    let irdepth = reader.read(irdepth_width(stream)) as u32;
+    (
+        address,
+        notify != msb,
+        updiscon != notify,
+        (irreport != updiscon).then(|| irdepth),
+    )
+}
+
+/* the optional time and context fields of the synchronization packets */
+fn read_context(reader: &mut BitReader, stream: &Stream) -> Option<u64> {
+    let config = &stream.etrace_tracer.config;
+    reader.read(config.time_width);
+    if config.context_width != 0 {
+        Some(reader.read(config.context_width))
+    } else {
+        None
+    }
+}
+
+pub fn decode_etrace_null(_: usize, _: &mut Stream) -> Result<usize, &str> {
+    Ok(1)
+}
+
+pub fn decode_etrace_packet(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let len = (stream.buff[pkt_offset] & LENGTH_MASK) as usize;
+    let payload = stream
+        .buff
+        .get(pkt_offset + 1..pkt_offset + 1 + len)
+        .ok_or("Truncated encapsulation packet")?
+        .to_vec();
+    let mut reader = BitReader::new(&payload);
+
+    /* the packet is framed, so a bad payload does not stop the decode of the next packet */
+    let result = match reader.read_header(&ETRACEPKTS) {
+        Some(pkt) => get_etrace_payload_func(pkt.name).unwrap()(&mut reader, stream)
+            .map_err(|msg| (msg, pkt.name)),
+        None => Err(("Reserved te_inst format", "te_inst")),
+    };
+    if let Err((msg, name)) = result {
+        eprintln!("{}", msg);
+        eprintln!(
+            "Cannot decode a packet of type {} at offset {}",
+            name, pkt_offset
+        );
+    }
+
+    Ok(1 + len)
+}
+
+/* format 0, subformat 0: the number of correctly predicted branches */
+fn decode_etrace_branch_count(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let count = reader.read(32) as u32;
+    let fmt = reader.read(2) as u8;
+    if fmt == 1 {
+        return Err("Reserved branch_fmt in the branch count packet");
+    }
+
+    tracer_etrace_branch_count(&stream.etrace_tracer, count, fmt);
+
+    if fmt != 0 {
+        let (address, notify, updiscon, irdepth) = read_address_flags(reader, stream);
+        tracer_etrace_address(
+            &mut stream.etrace_tracer,
+            address,
+            notify,
+            updiscon,
+            irdepth,
+            false,
+        );
+    }
+    Ok(())
+}
+
+/* format 0, subformat 1: the address is an index into the jump target cache */
+fn decode_etrace_jump_target_index(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let index = reader.read(stream.etrace_tracer.config.cache_size) as u32;
+    let nr_branches = reader.read(5) as u32;
+    if nr_branches != 0 {
+        let map = reader.read(branch_map_size(nr_branches));
+        tracer_etrace_branch_map(&stream.etrace_tracer, nr_branches, map);
+    }
+
+    tracer_etrace_jump_target(&mut stream.etrace_tracer, index);
+
+    Ok(())
+}
+
+/*
+ * Format 1: a branch map and then an address. With 0 branches the map is full, holds 31
+ * branches, and no address follows.
+ */
+fn decode_etrace_branch(reader: &mut BitReader, stream: &mut Stream) -> Result<(), &'static str> {
+    let nr_branches = reader.read(5) as u32;
+    let map = reader.read(branch_map_size(nr_branches));
+
+    if nr_branches == 0 {
+        tracer_etrace_branch_map(&stream.etrace_tracer, 31, map);
+        return Ok(());
+    }
+    tracer_etrace_branch_map(&stream.etrace_tracer, nr_branches, map);
+
+    let (address, notify, updiscon, irdepth) = read_address_flags(reader, stream);
+    tracer_etrace_address(
+        &mut stream.etrace_tracer,
+        address,
+        notify,
+        updiscon,
+        irdepth,
+        true,
+    );
+
+    Ok(())
+}
+
+/* format 2: an address only */
+fn decode_etrace_address(reader: &mut BitReader, stream: &mut Stream) -> Result<(), &'static str> {
+    let (address, notify, updiscon, irdepth) = read_address_flags(reader, stream);
+
+    tracer_etrace_address(
+        &mut stream.etrace_tracer,
+        address,
+        notify,
+        updiscon,
+        irdepth,
+        true,
+    );
+
+    Ok(())
+}
+
+/* format 3, subformat 0: the first instruction traced, with its full address */
+fn decode_etrace_sync_start(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let branch = reader.read(1) != 0;
+    let privilege = reader.read(stream.etrace_tracer.config.privilege_width) as u8;
+    let context = read_context(reader, stream);
+    let address = read_address(reader, stream);
+
+    tracer_etrace_sync_start(
+        &mut stream.etrace_tracer,
+        branch,
+        privilege,
+        context,
+        address,
+    );
+
+    Ok(())
+}
+
+/* format 3, subformat 1: an exception or interrupt */
+fn decode_etrace_sync_trap(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let config = stream.etrace_tracer.config;
+    let branch = reader.read(1) != 0;
+    let privilege = reader.read(config.privilege_width) as u8;
+    let context = read_context(reader, stream);
+    let ecause = reader.read(config.ecause_width) as u32;
+    let interrupt = reader.read(1) != 0;
+    let thaddr = reader.read(1) != 0;
+    let address = read_address(reader, stream);
+    let tval = reader.read(config.iaddress_width);
+
+    tracer_etrace_sync_trap(
+        &mut stream.etrace_tracer,
+        branch,
+        privilege,
+        context,
+        ecause,
+        interrupt,
+        thaddr,
+        address,
+        tval,
+    );
+
+    Ok(())
+}
+
+/* format 3, subformat 2: a change of privilege or context without an address */
+fn decode_etrace_sync_context(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let privilege = reader.read(stream.etrace_tracer.config.privilege_width) as u8;
+    let context = read_context(reader, stream);
+
+    tracer_etrace_sync_context(&mut stream.etrace_tracer, privilege, context);
+
+    Ok(())
+}
+
+/* format 3, subformat 3: the configuration of the encoder */
+fn decode_etrace_sync_support(
+    reader: &mut BitReader,
+    stream: &mut Stream,
+) -> Result<(), &'static str> {
+    let ienable = reader.read(1) != 0;
+    let encoder_mode = reader.read(1) as u32;
+    let qual_status = reader.read(2) as u8;
+    let ioptions = reader.read(stream.etrace_tracer.config.ioptions_width) as u32;
+
+    tracer_etrace_support(
+        &mut stream.etrace_tracer,
+        ienable,
+        encoder_mode,
+        qual_status,
+        ioptions,
+    );
+
+    Ok(())
+}
+
+/* the encapsulation header, the payload is found in ETRACEPKTS */
+pub const ETRACE_ENCAP_PKTS: [TracePkt; 2] = [
+    TracePkt::new("null", LENGTH_MASK, 0x00),
+    TracePkt::new("packet", 0x00, 0x00),
+];
+
+/* format in bits[1:0] of the payload, then subformat in bits[3:2] for formats 0 and 3 */
+pub const ETRACEPKTS: [TracePkt; 8] = [
+    TracePkt::new_bits("branch_count", 0x0f, 0x00, 4),
+    TracePkt::new_bits("jump_target_index", 0x0f, 0x04, 4),
+    TracePkt::new_bits("branch", 0x03, 0x01, 2),
+    TracePkt::new_bits("address", 0x03, 0x02, 2),
+    TracePkt::new_bits("sync_start", 0x0f, 0x03, 4),
+    TracePkt::new_bits("sync_trap", 0x0f, 0x07, 4),
+    TracePkt::new_bits("sync_context", 0x0f, 0x0b, 4),
+    TracePkt::new_bits("sync_support", 0x0f, 0x0f, 4),
+];
+
+/* there is no synchronization sequence in an encapsulated stream, so a bad header ends it */
+pub fn etrace_find_async(_: &[u8], _: usize) -> Option<usize> {
+    None
+}
+
+/* the encapsulation packets start at the start of the stream */
+pub fn etrace_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    /* SYNCING -> INSYNC */
+    stream.state = InSync;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* pack fields of (value, width) from the least significant bit into an encapsulation packet */
+    fn encapsulate(fields: &[(u64, u32)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let mut pos = 0;
+        for &(value, width) in fields {
+            for i in 0..width {
+                if pos % 8 == 0 {
+                    payload.push(0);
+                }
+                payload[pos / 8] |= (((value >> i) & 1) as u8) << (pos % 8);
+                pos += 1;
+            }
+        }
+        let mut packet = vec![payload.len() as u8];
+        packet.extend(payload);
+        packet
+    }
+
+    fn decode(stream: &mut Stream, fields: &[(u64, u32)]) {
+        stream.buff = encapsulate(fields);
+        let len = stream.buff.len();
+        assert_eq!(decode_etrace_packet(0, stream), Ok(len));
+    }
+
+    /* sync start, branch, M privilege and the address in bits[31:1] */
+    fn sync_start(stream: &mut Stream, address: u64) {
+        decode(stream, &[(0x3, 4), (1, 1), (3, 2), (address >> 1, 31)]);
+    }
+
+    /* format 2 with a differential address and no notify or updiscon */
+    fn address(stream: &mut Stream, offset: u64) {
+        decode(stream, &[(0x2, 2), (offset >> 1, 31), (0, 2)]);
+    }
+
+    fn jump_target_index(stream: &mut Stream, index: u64) {
+        decode(stream, &[(0x4, 4), (index, 8), (0, 5)]);
+    }
+
+    #[test]
+    fn jump_target_from_the_cache() {
+        let mut stream = Stream::new();
+
+        sync_start(&mut stream, 0x1000);
+        assert_eq!(stream.etrace_tracer.address, 0x1000);
+        address(&mut stream, 0x20);
+        assert_eq!(stream.etrace_tracer.address, 0x1020);
+        address(&mut stream, 0x4);
+        assert_eq!(stream.etrace_tracer.address, 0x1024);
+
+        /* 0x1020 is in entry 0x10 of the cache */
+        jump_target_index(&mut stream, 0x10);
+        assert!(stream.etrace_tracer.synced);
+        assert_eq!(stream.etrace_tracer.address, 0x1020);
+    }
+
+    #[test]
+    fn jump_target_not_in_the_cache() {
+        let mut stream = Stream::new();
+
+        sync_start(&mut stream, 0x1000);
+        jump_target_index(&mut stream, 0x10);
+        assert!(!stream.etrace_tracer.synced);
+    }
+
+    #[test]
+    fn jump_targets_only_in_the_cache() {
+        let mut stream = Stream::new();
+
+        sync_start(&mut stream, 0x1000);
+        /* the address of a mispredicted branch, from branch_fmt 3 */
+        decode(
+            &mut stream,
+            &[(0x0, 4), (2, 32), (3, 2), (0x20 >> 1, 31), (0, 2)],
+        );
+        assert_eq!(stream.etrace_tracer.address, 0x1020);
+        /* an address requested explicitly, notify differs from the sign of the address */
+        decode(&mut stream, &[(0x2, 2), (0x4 >> 1, 31), (1, 1), (1, 1)]);
+        assert_eq!(stream.etrace_tracer.address, 0x1024);
+        jump_target_index(&mut stream, 0x10);
+        assert!(!stream.etrace_tracer.synced);
+        sync_start(&mut stream, 0x1000);
+        jump_target_index(&mut stream, 0x12);
+        assert!(!stream.etrace_tracer.synced);
+
+        /* the target of an uninferable jump */
+        sync_start(&mut stream, 0x1000);
+        address(&mut stream, 0x24);
+        jump_target_index(&mut stream, 0x12);
+        assert!(stream.etrace_tracer.synced);
+        assert_eq!(stream.etrace_tracer.address, 0x1024);
+    }
+
+    #[test]
+    fn branch_fmt_outcomes() {
+        assert_eq!(BRANCH_FMT[0], "the next branch is mispredicted");
+        assert_eq!(
+            BRANCH_FMT[3],
+            "the address which follows is of a mispredicted branch"
+        );
+
+        /* branch_fmt 1 is reserved and no address follows branch_fmt 0 */
+        let mut stream = Stream::new();
+        sync_start(&mut stream, 0x1000);
+        decode(&mut stream, &[(0x0, 4), (2, 32), (1, 2), (0x20 >> 1, 31)]);
+        decode(&mut stream, &[(0x0, 4), (2, 32), (0, 2), (0x20 >> 1, 31)]);
+        assert_eq!(stream.etrace_tracer.address, 0x1000);
+    }
+
+    #[test]
+    fn return_stack_depth() {
+        let mut stream = Stream::new();
+        /* 4 return stack entries and a call counter of 2 bits make irdepth 5 bits wide */
+        stream.etrace_tracer.config.return_stack_size = 2;
+        stream.etrace_tracer.config.call_counter_size = 2;
+
+        /* irreport differs from updiscon */
+        let payload = encapsulate(&[(0x2, 2), (0x20 >> 1, 31), (0, 2), (1, 1), (0x5, 5)]);
+        let mut reader = BitReader::new(&payload[1..]);
+        reader.read(2);
+        assert_eq!(
+            read_address_flags(&mut reader, &stream),
+            (0x10, false, false, Some(0x5))
+        );
+
+        /* irreport and irdepth repeat updiscon */
+        let payload = encapsulate(&[(0x2, 2), (0x20 >> 1, 31), (0, 1), (1, 1), (1, 1), (0x1f, 5)]);
+        let mut reader = BitReader::new(&payload[1..]);
+        reader.read(2);
+        assert_eq!(
+            read_address_flags(&mut reader, &stream),
+            (0x10, false, true, None)
+        );
+
+        /* cut short after the address */
+        let payload = encapsulate(&[(0x2, 2), (0x20 >> 1, 31)]);
+        let mut reader = BitReader::new(&payload[1..]);
+        reader.read(2);
+        assert_eq!(
+            read_address_flags(&mut reader, &stream),
+            (0x10, false, false, None)
+        );
+    }
+
+    #[test]
+    fn sign_extended_address() {
+        let mut stream = Stream::new();
+
+        sync_start(&mut stream, 0x1000);
+        /* -0x10 cut short to the 6 bits which fill the byte of the header */
+        decode(&mut stream, &[(0x2, 2), (0x38, 6)]);
+        assert_eq!(stream.etrace_tracer.address, 0xff0);
+    }
+
+    #[test]
+    fn config_widths() {
+        let mut config = EtraceConfig::new();
+        assert!(etrace_check_config(&config).is_ok());
+        config.cache_size = 17;
+        assert!(etrace_check_config(&config).is_err());
+        config = EtraceConfig::new();
+        config.iaddress_lsb = 32;
+        assert!(etrace_check_config(&config).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * The memory image of the traced program. The trace only tells whether the branches are taken,
+ * so the image is needed to find the branch instruction each atom stands for.
+ * Only A64 instructions are decoded.
+ */
+
+/* the A64 branch instructions, as far as the trace of their targets is concerned */
+#[derive(Clone, Copy, PartialEq)]
+pub enum A64Branch {
+    /* B, BL, B.cond, BC.cond, CBZ, CBNZ, TBZ and TBNZ, whose target is in the instruction */
+    Direct { target: u64, link: bool },
+    /* BR, BLR, ERET and their pointer authentication variants, whose target is traced */
+    Indirect { link: bool },
+    /* RET and its pointer authentication variants */
+    Return,
+}
+
+#[derive(Clone)]
+pub struct Image {
+    /* the start address and the contents of each region of the image */
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn add_region(&mut self, address: u64, data: Vec<u8>) {
+        self.regions.push((address, data));
+    }
+
+    /* the region which holds address, and the offset of address in it */
+    fn find_region(&self, address: u64) -> Option<(&[u8], usize)> {
+        self.regions.iter().find_map(|(start, data)| {
+            let offset = address.checked_sub(*start)?;
+            if offset < data.len() as u64 {
+                Some((data.as_slice(), offset as usize))
+            } else {
+                None
+            }
+        })
+    }
+
+    /*
+     * Find the first branch instruction at or after address, which must be in the same region.
+     * Return its address and what kind of branch it is.
+     */
+    pub fn next_a64_branch(&self, address: u64) -> Option<(u64, A64Branch)> {
+        if (address & 0x3) != 0 {
+            return None;
+        }
+        let (data, offset) = self.find_region(address)?;
+
+        data[offset..]
+            .chunks_exact(4)
+            .enumerate()
+            .find_map(|(i, bytes)| {
+                let pc = address + 4 * i as u64;
+                let insn = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                decode_a64_branch(pc, insn).map(|branch| (pc, branch))
+            })
+    }
+}
+
+/* the target of a direct branch at pc, whose word offset is the bits-wide field at lsb */
+fn branch_target(pc: u64, insn: u32, lsb: u32, bits: u32) -> u64 {
+    let field = (insn >> lsb) & ((1 << bits) - 1);
+    /* sign extend the offset */
+    let offset = ((field << (32 - bits)) as i32 >> (32 - bits)) as i64;
+    pc.wrapping_add((offset * 4) as u64)
+}
+
+/* tell what kind of branch the A64 instruction at pc is, None if it is not a branch */
+pub fn decode_a64_branch(pc: u64, insn: u32) -> Option<A64Branch> {
+    if (insn & 0x7c000000) == 0x14000000 {
+        /* B and BL */
+        Some(A64Branch::Direct {
+            target: branch_target(pc, insn, 0, 26),
+            link: (insn & 0x80000000) != 0,
+        })
+    } else if (insn & 0xff000000) == 0x54000000 {
+        /* B.cond and BC.cond */
+        Some(A64Branch::Direct {
+            target: branch_target(pc, insn, 5, 19),
+            link: false,
+        })
+    } else if (insn & 0x7e000000) == 0x34000000 {
+        /* CBZ and CBNZ */
+        Some(A64Branch::Direct {
+            target: branch_target(pc, insn, 5, 19),
+            link: false,
+        })
+    } else if (insn & 0x7e000000) == 0x36000000 {
+        /* TBZ and TBNZ */
+        Some(A64Branch::Direct {
+            target: branch_target(pc, insn, 5, 14),
+            link: false,
+        })
+    } else if (insn & 0xfe000000) == 0xd6000000 {
+        /* the unconditional branches to a register, told apart by opc */
+        match (insn >> 21) & 0xf {
+            0 | 8 => Some(A64Branch::Indirect { link: false }),
+            1 | 9 => Some(A64Branch::Indirect { link: true }),
+            2 => Some(A64Branch::Return),
+            4 => Some(A64Branch::Indirect { link: false }),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a64_branches() {
+        /* bl #-0x10 */
+        assert!(
+            decode_a64_branch(0x1010, 0x97fffffc)
+                == Some(A64Branch::Direct {
+                    target: 0x1000,
+                    link: true
+                })
+        );
+        /* b.ne #0x20 */
+        assert!(
+            decode_a64_branch(0x1000, 0x54000101)
+                == Some(A64Branch::Direct {
+                    target: 0x1020,
+                    link: false
+                })
+        );
+        /* tbz w0, #3, #-0x8 */
+        assert!(
+            decode_a64_branch(0x1008, 0x361fffc0)
+                == Some(A64Branch::Direct {
+                    target: 0x1000,
+                    link: false
+                })
+        );
+        /* blr x1, br x2, ret, retaa, eret */
+        assert!(decode_a64_branch(0, 0xd63f0020) == Some(A64Branch::Indirect { link: true }));
+        assert!(decode_a64_branch(0, 0xd61f0040) == Some(A64Branch::Indirect { link: false }));
+        assert!(decode_a64_branch(0, 0xd65f03c0) == Some(A64Branch::Return));
+        assert!(decode_a64_branch(0, 0xd65f0bff) == Some(A64Branch::Return));
+        assert!(decode_a64_branch(0, 0xd69f03e0) == Some(A64Branch::Indirect { link: false }));
+        /* nop, add x0, x0, #1 */
+        assert!(decode_a64_branch(0, 0xd503201f).is_none());
+        assert!(decode_a64_branch(0, 0x91000400).is_none());
+    }
+    #[test]
+    fn next_branch_in_the_region() {
+        let mut image = Image::new();
+        /* nop, nop, ret */
+        let code = [0xd503201fu32, 0xd503201f, 0xd65f03c0];
+        image.add_region(
+            0x1000,
+            code.iter().flat_map(|insn| insn.to_le_bytes()).collect(),
+        );
+
+        assert!(image.next_a64_branch(0x1000) == Some((0x1008, A64Branch::Return)));
+        assert!(image.next_a64_branch(0x1008) == Some((0x1008, A64Branch::Return)));
+        assert!(image.next_a64_branch(0x100c).is_none());
+        assert!(image.next_a64_branch(0x0ffc).is_none());
+    }
+}
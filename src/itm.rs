@@ -0,0 +1,323 @@
+use crate::etmv4::decode_continuation_field;
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_itm::*;
+
+const C_BIT: u8 = 0x80;
+
+pub fn get_itm_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "sync" => Some(decode_itm_sync),
+        "overflow" => Some(decode_itm_overflow),
+        "local_timestamp_1" | "local_timestamp_2" => Some(decode_itm_local_timestamp),
+        "global_timestamp_1" => Some(decode_itm_global_timestamp_1),
+        "global_timestamp_2" => Some(decode_itm_global_timestamp_2),
+        "extension" => Some(decode_itm_extension),
+        "instrumentation" => Some(decode_itm_instrumentation),
+        "hardware" => Some(decode_itm_hardware),
+        _ => None,
+    }
+}
+
+/* the payload size of a source packet, in bits[1:0] of its header */
+fn source_payload_size(header: u8) -> usize {
+    match header & 0x3 {
+        1 => 1,
+        2 => 2,
+        _ => 4,
+    }
+}
+
+/* read the little-endian payload of a source packet */
+fn decode_source_payload(stream: &Stream, pkt_offset: usize) -> Result<u32, &'static str> {
+    let size = source_payload_size(stream.buff[pkt_offset]);
+    let payload = stream
+        .buff
+        .get(pkt_offset + 1..pkt_offset + 1 + size)
+        .ok_or("Truncated source packet")?;
+    Ok(payload
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, &b)| value | ((b as u32) << (8 * i))))
+}
+
+pub fn decode_itm_sync(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    /* at least 47 bits of 0 followed by a bit of 1 */
+    let nr_zeros = stream.buff[pkt_offset..]
+        .iter()
+        .take_while(|&&b| b == 0x00)
+        .count();
+
+    if nr_zeros < 5 || stream.buff.get(pkt_offset + nr_zeros) != Some(&0x80) {
+        return Err("Invalid synchronization packet");
+    }
+
+    tracer_itm_sync(&mut stream.itm_tracer);
+
+    Ok(nr_zeros + 1)
+}
+
+pub fn decode_itm_overflow(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_itm_overflow(&stream.itm_tracer);
+    Ok(1)
+}
+
+/*
+ * LTS1 0b11TC0000 is followed by up to 4 bytes of timestamp and LTS2 0b0TTT0000
+ * carries a timestamp of 1 to 6 in itself.
+ */
+pub fn decode_itm_local_timestamp(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+
+    if (header & C_BIT) == 0 {
+        tracer_itm_local_timestamp(&mut stream.itm_tracer, ((header >> 4) & 0x7) as u32, 0);
+        return Ok(1);
+    }
+
+    let (delta, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 4)
+        .ok_or("More than 4 bytes in the local timestamp packet")?;
+
+    tracer_itm_local_timestamp(&mut stream.itm_tracer, delta, (header >> 4) & 0x3);
+
+    Ok(1 + len)
+}
+
+/* GTS1 carries bits[25:0] of the global timestamp, and the ClkCh and Wrap flags in its 4th byte */
+pub fn decode_itm_global_timestamp_1(
+    pkt_offset: usize,
+    stream: &mut Stream,
+) -> Result<usize, &str> {
+    let (value, len) = decode_continuation_field(&stream.buff, pkt_offset + 1, 4)
+        .ok_or("More than 4 bytes in the global timestamp packet")?;
+    let (clock_change, wrap) = if len == 4 {
+        ((value & (1 << 26)) != 0, (value & (1 << 27)) != 0)
+    } else {
+        (false, false)
+    };
+    let nr_bits = if len == 4 { 26 } else { 7 * len as u32 };
+
+    tracer_itm_global_timestamp(
+        &mut stream.itm_tracer,
+        (value & 0x3ffffff) as u64,
+        0,
+        nr_bits,
+        clock_change,
+        wrap,
+    );
+
+    Ok(1 + len)
+}
+
+/* GTS2 carries bits[47:26], or bits[63:26] in its 6-byte form, of the global timestamp */
+pub fn decode_itm_global_timestamp_2(
+    pkt_offset: usize,
+    stream: &mut Stream,
+) -> Result<usize, &str> {
+    let mut value = 0;
+    let mut index = 1;
+
+    loop {
+        let data = *stream
+            .buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated global timestamp packet")?;
+        value |= ((data & !C_BIT) as u64) << (7 * (index - 1));
+        index += 1;
+        if (data & C_BIT) == 0 {
+            break;
+        }
+        if index > 6 {
+            return Err("More than 6 bytes in the global timestamp packet");
+        }
+    }
+
+    tracer_itm_global_timestamp(
+        &mut stream.itm_tracer,
+        value,
+        26,
+        if index - 1 > 4 { 38 } else { 22 },
+        false,
+        false,
+    );
+
+    Ok(index)
+}
+
+/* 0bCEEE1S00: EX[2:0] in bits[6:4] and up to 4 more bytes, SH in bit[2] */
+pub fn decode_itm_extension(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let mut value = ((header >> 4) & 0x7) as u32;
+    let mut len = 0;
+
+    if (header & C_BIT) != 0 {
+        let (ex, nr_bytes) = decode_continuation_field(&stream.buff, pkt_offset + 1, 4)
+            .ok_or("More than 4 bytes in the extension packet")?;
+        value |= ex << 3;
+        len = nr_bytes;
+    }
+
+    tracer_itm_extension(&mut stream.itm_tracer, value, (header & 0x04) != 0);
+
+    Ok(1 + len)
+}
+
+/* 0bAAAAA0SS: a write of 1, 2 or 4 bytes to stimulus port A */
+pub fn decode_itm_instrumentation(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let size = source_payload_size(header);
+    let data = decode_source_payload(stream, pkt_offset)?;
+
+    tracer_itm_instrumentation(&mut stream.itm_tracer, header >> 3, size, data);
+
+    Ok(1 + size)
+}
+
+/*
+ * 0bAAAAA1SS: a DWT packet with discriminator ID A.
+ * 0 is the event counter packet, 1 exception trace and 2 the periodic PC sample.
+ * 8 to 23 are data trace packets, 0b01NNT for the PC value (T = 0) or the address offset (T = 1)
+ * and 0b10NNW for the data value read (W = 0) or written (W = 1) at comparator NN.
+ */
+pub fn decode_itm_hardware(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let id = header >> 3;
+    let size = source_payload_size(header);
+    let data = decode_source_payload(stream, pkt_offset)?;
+    let tracer = &stream.itm_tracer;
+
+    match id {
+        0 => tracer_itm_event_counter(tracer, data as u8),
+        1 => tracer_itm_exception(tracer, (data & 0x1ff) as u16, ((data >> 12) & 0x3) as u8),
+        2 => tracer_itm_pc_sample(tracer, if size == 4 { Some(data) } else { None }),
+        8..=15 if (id & 1) == 0 => tracer_itm_data_pc(tracer, (id >> 1) & 0x3, data),
+        8..=15 => tracer_itm_data_address(tracer, (id >> 1) & 0x3, data as u16),
+        16..=23 => tracer_itm_data_value(tracer, (id >> 1) & 0x3, (id & 1) != 0, size, data),
+        _ => tracer_itm_hardware(tracer, id, data),
+    }
+
+    Ok(1 + size)
+}
+
+pub const ITMPKTS: [TracePkt; 13] = [
+    TracePkt::new("sync", 0xff, 0x00),
+    TracePkt::new("overflow", 0xff, 0x70),
+    TracePkt::new("local_timestamp_2", 0x8f, 0x00),
+    TracePkt::new("local_timestamp_1", 0xcf, 0xc0),
+    TracePkt::new("global_timestamp_1", 0xff, 0x94),
+    TracePkt::new("global_timestamp_2", 0xff, 0xb4),
+    TracePkt::new("extension", 0x0b, 0x08),
+    TracePkt::new("instrumentation", 0x07, 0x01),
+    TracePkt::new("instrumentation", 0x07, 0x02),
+    TracePkt::new("instrumentation", 0x07, 0x03),
+    TracePkt::new("hardware", 0x07, 0x05),
+    TracePkt::new("hardware", 0x07, 0x06),
+    TracePkt::new("hardware", 0x07, 0x07),
+];
+
+/* find the next synchronization packet, 5 bytes of 0x00 followed by 0x80, at or after from */
+pub fn itm_find_async(buff: &[u8], from: usize) -> Option<usize> {
+    if from >= buff.len() {
+        return None;
+    }
+    buff[from..]
+        .windows(6)
+        .position(|w| w[..5].iter().all(|&b| b == 0) && w[5] == 0x80)
+        .map(|i| from + i)
+}
+
+pub fn itm_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    match itm_find_async(&stream.buff, 0) {
+        Some(i) => {
+            /* SYNCING -> INSYNC */
+            stream.state = InSync;
+            Ok(i)
+        }
+        None => Err("No synchronization packet in the ITM stream"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* decode the packet at the offset as the stream decoder finds it */
+    fn decode_at(stream: &mut Stream, offset: usize) -> Result<usize, &str> {
+        let c = stream.buff[offset];
+        let pkt = ITMPKTS
+            .iter()
+            .find(|pkt| (c & pkt.mask) == pkt.val)
+            .unwrap();
+        get_itm_decode_func(pkt.name).unwrap()(offset, stream)
+    }
+
+    #[test]
+    fn find_sync_packet() {
+        let buff = [0x01, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x70];
+        assert_eq!(itm_find_async(&buff, 0), Some(3));
+        assert_eq!(itm_find_async(&buff, 4), None);
+
+        let mut stream = Stream::new();
+        stream.buff = buff.to_vec();
+        assert_eq!(decode_at(&mut stream, 3), Ok(6));
+        assert!(decode_at(&mut stream, 4).is_err());
+    }
+
+    #[test]
+    fn port_text_in_pages() {
+        let mut stream = Stream::new();
+        /* page 1, then "hi" written a byte at a time to port 3 of it, without a newline */
+        stream.buff = vec![0x18, 0x19, b'h', 0x19, b'i'];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(1));
+        assert_eq!(stream.itm_tracer.page, 1);
+        assert_eq!(decode_at(&mut stream, 1), Ok(2));
+        assert_eq!(decode_at(&mut stream, 3), Ok(2));
+        assert_eq!(stream.itm_tracer.port_text[35], "hi");
+
+        tracer_itm_flush(&mut stream.itm_tracer);
+        assert!(stream.itm_tracer.port_text[35].is_empty());
+    }
+
+    #[test]
+    fn newline_ends_port_text() {
+        let mut stream = Stream::new();
+        stream.buff = vec![0x01, b'o', 0x01, b'k', 0x01, b'\r', 0x01, b'\n'];
+
+        for offset in (0..8).step_by(2) {
+            assert_eq!(decode_at(&mut stream, offset), Ok(2));
+        }
+        assert!(stream.itm_tracer.port_text[0].is_empty());
+    }
+
+    #[test]
+    fn global_timestamp_in_two_packets() {
+        let mut stream = Stream::new();
+        /* GTS1 with bits[25:0] = 0x3ffffff and the Wrap flag, then GTS2 with bits[47:26] = 1 */
+        stream.buff = vec![0x94, 0xff, 0xff, 0xff, 0x5f, 0xb4, 0x01];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(5));
+        assert_eq!(stream.itm_tracer.global_timestamp, 0x3ffffff);
+        assert_eq!(decode_at(&mut stream, 5), Ok(2));
+        assert_eq!(stream.itm_tracer.global_timestamp, 0x7ffffff);
+    }
+
+    #[test]
+    fn local_timestamps() {
+        let mut stream = Stream::new();
+        /* LTS2 of 3, then LTS1 of 0x81 in two bytes */
+        stream.buff = vec![0x30, 0xc0, 0x81, 0x01];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(1));
+        assert_eq!(decode_at(&mut stream, 1), Ok(3));
+        assert_eq!(stream.itm_tracer.local_timestamp, 3 + 0x81);
+    }
+
+    #[test]
+    fn truncated_source_packet() {
+        let mut stream = Stream::new();
+        /* a 4-byte write to port 0 with 2 bytes of its payload */
+        stream.buff = vec![0x03, 0x12, 0x34];
+        assert!(decode_at(&mut stream, 0).is_err());
+    }
+}
@@ -1,21 +1,254 @@
 extern crate core;
 use std::fs::File;
 use std::io::Read;
+use std::num::ParseIntError;
 use std::path::Path;
+use std::process;
 
-use clap::{arg, command};
+use clap::{arg, command, Arg};
+
+use crate::etmv4::{etmv4_check_version, ETMV4_LATEST_VERSION};
+use crate::tracer_etmv4::{tracer_configure, tracer_enable_ete};
+use crate::tracer_etrace::etrace_check_config;
 
 mod stream;
 mod tracer_etmv4;
 mod etb_format;
 mod etmv4;
 mod pktproto;
+mod ptm;
+mod tracer_ptm;
+mod etmv3;
+mod tracer_etmv3;
+mod stpv2;
+mod tracer_stpv2;
+mod itm;
+mod tracer_itm;
+mod etrace;
+mod tracer_etrace;
+mod spe;
+mod tracer_spe;
+mod image;
+
+/* the trace unit registers which the decoder configuration can be derived from */
+const REGISTERS: [(&str, &str); 9] = [
+    (
+        "trcidr0",
+        "Value of TRCIDR0 (conditional tracing type, commit mode)",
+    ),
+    ("trcidr2", "Value of TRCIDR2 (context ID and VMID sizes)"),
+    ("trcidr8", "Value of TRCIDR8 (maximum speculation depth)"),
+    ("trcidr9", "Value of TRCIDR9 (number of P0 right-hand keys)"),
+    (
+        "trcidr10",
+        "Value of TRCIDR10 (number of P1 right-hand keys)",
+    ),
+    (
+        "trcidr11",
+        "Value of TRCIDR11 (number of special P1 right-hand keys)",
+    ),
+    (
+        "trcidr12",
+        "Value of TRCIDR12 (number of conditional instruction right-hand keys)",
+    ),
+    (
+        "trcidr13",
+        "Value of TRCIDR13 (number of special conditional instruction right-hand keys)",
+    ),
+    ("trcconfigr", "Value of TRCCONFIGR"),
+];
+
+/* the parameters of the RISC-V E-Trace encoder which set the widths of the packet fields */
+const ETRACE_PARAMETERS: [(&str, &str); 9] = [
+    (
+        "etrace-iaddress-lsb",
+        "Number of low order address bits which are not sent (default 1)",
+    ),
+    (
+        "etrace-privilege-width",
+        "Width of the privilege field (default 2)",
+    ),
+    (
+        "etrace-ecause-width",
+        "Width of the ecause field (default 6)",
+    ),
+    (
+        "etrace-context-width",
+        "Width of the context field, 0 if it is not sent (default 0)",
+    ),
+    (
+        "etrace-time-width",
+        "Width of the time field, 0 if it is not sent (default 0)",
+    ),
+    (
+        "etrace-cache-size",
+        "Log2 of the number of jump target cache entries (default 8)",
+    ),
+    (
+        "etrace-ioptions-width",
+        "Width of the ioptions field of the support packet (default 5)",
+    ),
+    (
+        "etrace-return-stack-size",
+        "Log2 of the number of implicit return stack entries, 0 if there is none (default 0)",
+    ),
+    (
+        "etrace-call-counter-size",
+        "Width of the nested call counter, 0 if there is none (default 0)",
+    ),
+];
+
+/* parse a register value given in hexadecimal with a 0x prefix or in decimal */
+fn parse_register_value(value: &str) -> Result<u32, ParseIntError> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    }
+}
+
+/* parse an architecture version given as 4.<minor>, e.g. 4.3 */
+fn parse_etmv4_version(version: &str) -> Result<u8, String> {
+    match version.strip_prefix("4.").map(|minor| minor.parse::<u8>()) {
+        Some(Ok(minor)) if minor <= ETMV4_LATEST_VERSION => Ok(minor),
+        _ => Err(format!(
+            "Invalid ETMv4 version {}: expect 4.0 to 4.{}",
+            version, ETMV4_LATEST_VERSION
+        )),
+    }
+}
+
+/* parse a trace ID, which the formatter allows from 0x01 to 0x6f */
+fn parse_trace_id(id: &str) -> Result<u8, String> {
+    match parse_register_value(id) {
+        Ok(id) if (0x01..=0x6f).contains(&id) => Ok(id as u8),
+        _ => Err(format!("Invalid trace ID {}: expect 0x01 to 0x6f", id)),
+    }
+}
+
+/* parse an address given in hexadecimal with a 0x prefix or in decimal */
+fn parse_address(address: &str) -> Result<u64, ParseIntError> {
+    match address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => address.parse::<u64>(),
+    }
+}
+
+/*
+ * parse the address of a branch with link given as <address>[:<size>], the size of the
+ * instruction is 4 bytes unless it is given, e.g. 2 for a T32 BLX <Rm>
+ */
+fn parse_link_branch(branch: &str) -> Result<(u64, u64), String> {
+    let (address, size) = branch.split_once(':').unwrap_or((branch, "4"));
+    match (parse_address(address), size.parse::<u64>()) {
+        (Ok(address), Ok(size)) if size == 2 || size == 4 => Ok((address, size)),
+        _ => Err(format!(
+            "Invalid branch with link {}: expect <address>[:<size>], with a size of 2 or 4",
+            branch
+        )),
+    }
+}
+
+/* parse a region of the program image given as <address>:<file>, and read the file */
+fn parse_image(image: &str) -> Result<(u64, Vec<u8>), String> {
+    let (address, path) = image
+        .split_once(':')
+        .ok_or(format!("Invalid image {}: expect <address>:<file>", image))?;
+    let address = parse_address(address)
+        .map_err(|msg| format!("Invalid image address {}: {}", address, msg))?;
+    let data = std::fs::read(path).map_err(|msg| format!("Cannot read {}: {}", path, msg))?;
+    Ok((address, data))
+}
+
+/* parse an event name given as <number>=<name> */
+fn parse_event_name(event: &str) -> Result<(usize, String), String> {
+    let (nr, name) = event.split_once('=').ok_or(format!(
+        "Invalid event name {}: expect <number>=<name>",
+        event
+    ))?;
+    match nr.trim().parse::<usize>() {
+        Ok(nr) if nr < 4 => Ok((nr, name.to_string())),
+        _ => Err(format!("Invalid event number {}: expect 0 to 3", nr)),
+    }
+}
 
 fn main() {
     let mut input_path: Option<&Path> = None;
 
     let matches = command!()
         .arg(arg!([input] "The file of the ETM stream").required(true))
+        .arg(arg!(-s --speculative "Show P0 elements as they arrive instead of when they are committed"))
+        .arg(arg!(-m --"m-profile" "Decode the trace of an M-profile (ARMv6-M, ARMv7-M, ARMv8-M) PE"))
+        .arg(
+            arg!(-e --event <EVENT> "Name an event, e.g. 2=\"L2 refill threshold\"")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(arg!(--ete "Decode the trace of an ETE (Embedded Trace Extension) trace unit"))
+        .arg(arg!(-p --ptm "Decode the trace of a PTM (PFTv1.0, PFTv1.1) instead of an ETMv4"))
+        .arg(arg!(--etmv3 "Decode the trace of an ETMv3 instead of an ETMv4").conflicts_with("ptm"))
+        .arg(
+            arg!(-c --"contextid-size" <SIZE> "Size in bytes of the PTM or ETMv3 context ID, 0, 1, 2 or 4 (default 4)")
+                .required(false),
+        )
+        .arg(arg!(--"cycle-accurate" "The PTM or ETMv3 trace was captured in cycle-accurate mode"))
+        .arg(
+            arg!(--"stm-id" <ID> "Decode the stream of this trace ID as the STPv2 output of an STM")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--"itm-id" <ID> "Decode the stream of this trace ID as the ITM and DWT packets of a Cortex-M")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--"etrace-id" <ID> "Decode the stream of this trace ID as RISC-V E-Trace packets")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--"etrace-address-width" <BITS> "Width of the instruction addresses of the RISC-V E-Trace encoder, 32 or 64 (default 32)")
+                .required(false),
+        )
+        .args(ETRACE_PARAMETERS.map(|(name, help)| {
+            Arg::new(name)
+                .long(name)
+                .value_name("BITS")
+                .takes_value(true)
+                .help(help)
+        }))
+        .arg(
+            arg!(--spe "The input is a raw buffer of SPE (Statistical Profiling Extension) records instead of formatted trace")
+                .conflicts_with_all(&["ptm", "etmv3"]),
+        )
+        .arg(
+            arg!(--"link-branch" <ADDR> "Address of a branch with link, as <address>[:<size>], whose return address is pushed onto the return stack")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--image <IMAGE> "Raw binary of the traced AArch64 program, as <address>:<file>, to follow the atoms through for the return stack")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(arg!(-b --"branch-records" "List the source and target pairs of the ETE source address packets as they are resolved"))
+        .arg(
+            arg!(--"etmv4-version" <VERSION> "Architecture version of the trace unit, 4.0 to 4.6 (default 4.6)")
+                .required(false),
+        )
+        .args(REGISTERS.map(|(name, help)| {
+            Arg::new(name)
+                .long(name)
+                .value_name("VALUE")
+                .takes_value(true)
+                .help(help)
+        }))
         .get_matches();
 
     if let Some(input) = matches.value_of("input") {
@@ -24,7 +257,169 @@ fn main() {
 
     let mut input_file = File::open(input_path.unwrap()).unwrap();
     let mut stream = stream::Stream::new();
+    stream.tracer.show_speculative = matches.is_present("speculative");
+    stream.tracer.m_profile = matches.is_present("m-profile");
+    stream.tracer.show_branch_records = matches.is_present("branch-records");
+    if matches.is_present("ptm") {
+        stream.protocol = stream::Protocol::Ptm;
+    }
+    if matches.is_present("etmv3") {
+        stream.protocol = stream::Protocol::Etmv3;
+    }
+    for (option, protocol) in [
+        ("stm-id", stream::Protocol::Stpv2),
+        ("itm-id", stream::Protocol::Itm),
+        ("etrace-id", stream::Protocol::Etrace),
+    ] {
+        if let Some(ids) = matches.values_of(option) {
+            for id in ids {
+                match parse_trace_id(id) {
+                    Ok(id) => stream.trace_id_protocols.push((id, protocol)),
+                    Err(msg) => {
+                        eprintln!("{}", msg);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(width) = matches.value_of("etrace-address-width") {
+        match width.parse::<u32>() {
+            Ok(width) if width == 32 || width == 64 => {
+                stream.etrace_tracer.config.iaddress_width = width
+            }
+            _ => {
+                eprintln!("Invalid E-Trace address width {}: expect 32 or 64", width);
+                process::exit(1);
+            }
+        }
+    }
+    let config = &mut stream.etrace_tracer.config;
+    for (name, param) in [
+        ("etrace-iaddress-lsb", &mut config.iaddress_lsb),
+        ("etrace-privilege-width", &mut config.privilege_width),
+        ("etrace-ecause-width", &mut config.ecause_width),
+        ("etrace-context-width", &mut config.context_width),
+        ("etrace-time-width", &mut config.time_width),
+        ("etrace-cache-size", &mut config.cache_size),
+        ("etrace-ioptions-width", &mut config.ioptions_width),
+        ("etrace-return-stack-size", &mut config.return_stack_size),
+        ("etrace-call-counter-size", &mut config.call_counter_size),
+    ] {
+        if let Some(value) = matches.value_of(name) {
+            match value.parse::<u32>() {
+                Ok(value) => *param = value,
+                Err(msg) => {
+                    eprintln!("Invalid value of --{}: {}", name, msg);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Err(msg) = etrace_check_config(&stream.etrace_tracer.config) {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+    if let Some(size) = matches.value_of("contextid-size") {
+        match size.parse::<u32>() {
+            Ok(size) if size == 0 || size == 1 || size == 2 || size == 4 => {
+                stream.ptm_tracer.context_id_size = size;
+            }
+            _ => {
+                eprintln!("Invalid context ID size {}: expect 0, 1, 2 or 4", size);
+                process::exit(1);
+            }
+        }
+    }
+    stream.ptm_tracer.cycle_accurate = matches.is_present("cycle-accurate");
+    if matches.is_present("ete") {
+        if let Err(msg) = tracer_enable_ete(&mut stream.tracer) {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }
+    }
+    if REGISTERS.iter().any(|(name, _)| matches.is_present(name)) {
+        /* registers which are not given keep the values of the trace unit type */
+        let mut config = stream.tracer.config;
+        for (name, reg) in [
+            ("trcidr0", &mut config.trcidr0),
+            ("trcidr2", &mut config.trcidr2),
+            ("trcidr8", &mut config.trcidr8),
+            ("trcidr9", &mut config.trcidr9),
+            ("trcidr10", &mut config.trcidr10),
+            ("trcidr11", &mut config.trcidr11),
+            ("trcidr12", &mut config.trcidr12),
+            ("trcidr13", &mut config.trcidr13),
+            ("trcconfigr", &mut config.trcconfigr),
+        ] {
+            if let Some(value) = matches.value_of(name) {
+                match parse_register_value(value) {
+                    Ok(value) => *reg = value,
+                    Err(msg) => {
+                        eprintln!("Invalid value of --{}: {}", name, msg);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+        if let Err(msg) = tracer_configure(&mut stream.tracer, &config) {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }
+    }
+    if let Some(version) = matches.value_of("etmv4-version") {
+        match parse_etmv4_version(version) {
+            Ok(minor) => stream.etmv4_version = minor,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(msg) = etmv4_check_version(&stream) {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+    if let Some(events) = matches.values_of("event") {
+        for event in events {
+            match parse_event_name(event) {
+                Ok((nr, name)) => stream.tracer.event_names[nr] = Some(name),
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(branches) = matches.values_of("link-branch") {
+        for branch in branches {
+            match parse_link_branch(branch) {
+                Ok(branch) => stream.tracer.link_branches.push(branch),
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(images) = matches.values_of("image") {
+        for image in images {
+            match parse_image(image) {
+                Ok((address, data)) => stream.tracer.image.add_region(address, data),
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    process::exit(1);
+                }
+            }
+        }
+    }
     input_file.read_to_end(&mut stream.buff).unwrap();
 
-    etb_format::decode_etb_stream(stream);
+    if matches.is_present("spe") {
+        /* SPE buffers are written to memory by the PE, not through the trace formatter */
+        stream.protocol = stream::Protocol::Spe;
+        stream::decode_stream(&mut stream);
+    } else {
+        etb_format::decode_etb_stream(stream);
+    }
 }
@@ -1,13 +1,145 @@
+use crate::stream::Stream;
+
 pub type PktHeader = u8;
 
+/* decode the packet at the offset and return its length */
+pub type DecodeFunc = fn(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str>;
+
+/* decode the rest of a bit-oriented packet after its header */
+pub type BitDecodeFunc =
+    fn(reader: &mut BitReader, stream: &mut Stream) -> Result<(), &'static str>;
+
 pub struct TracePkt {
     pub name: &'static str,
     pub mask: PktHeader,
     pub val: PktHeader,
+    /* the size of the header, a whole byte unless the protocol is bit-oriented */
+    pub nr_bits: u32,
 }
 
 impl TracePkt {
     pub const fn new(name: &'static str, mask: PktHeader, val: PktHeader) -> Self {
-        Self { name, mask, val }
+        Self {
+            name,
+            mask,
+            val,
+            nr_bits: 8,
+        }
+    }
+
+    /* a header of nr_bits bits, matched in the low order bits of the header value */
+    pub const fn new_bits(
+        name: &'static str,
+        mask: PktHeader,
+        val: PktHeader,
+        nr_bits: u32,
+    ) -> Self {
+        Self {
+            name,
+            mask,
+            val,
+            nr_bits,
+        }
+    }
+}
+
+/*
+ * Read the fields of a bit-oriented packet, from the least significant bit of the first byte
+ * upwards. Packets may be cut short where the rest of their bits would all repeat the last bit
+ * which is sent, so the last bit is returned for any bit past the end of the packet.
+ */
+pub struct BitReader<'a> {
+    buff: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buff: &'a [u8]) -> Self {
+        Self { buff, pos: 0 }
+    }
+
+    fn bit(&self, index: usize) -> u64 {
+        let nr_bits = self.buff.len() * 8;
+        if nr_bits == 0 {
+            return 0;
+        }
+        let index = index.min(nr_bits - 1);
+        ((self.buff[index / 8] >> (index % 8)) & 1) as u64
+    }
+
+    /* return the next nr_bits bits, up to 64, without consuming them */
+    pub fn peek(&self, nr_bits: u32) -> u64 {
+        (0..nr_bits as usize).fold(0, |value, i| value | (self.bit(self.pos + i) << i))
+    }
+
+    pub fn read(&mut self, nr_bits: u32) -> u64 {
+        let value = self.peek(nr_bits);
+        self.pos += nr_bits as usize;
+        value
+    }
+
+    /* find the packet of the header in a table of bit-oriented packets and consume its header */
+    pub fn read_header<'t>(&mut self, pkts: &'t [TracePkt]) -> Option<&'t TracePkt> {
+        let header = self.peek(8) as PktHeader;
+        let pkt = pkts.iter().find(|pkt| (header & pkt.mask) == pkt.val)?;
+        self.pos += pkt.nr_bits as usize;
+        Some(pkt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_from_the_least_significant_bit() {
+        let buff = [0xa5, 0x3c];
+        let mut reader = BitReader::new(&buff);
+
+        assert_eq!(reader.peek(4), 0x5);
+        assert_eq!(reader.read(4), 0x5);
+        /* a field across the byte boundary */
+        assert_eq!(reader.read(8), 0xca);
+        assert_eq!(reader.read(4), 0x3);
+    }
+
+    #[test]
+    fn last_bit_repeats_past_the_end() {
+        /* a negative field cut short after its sign bit reads as sign-extended */
+        let mut reader = BitReader::new(&[0x80]);
+        assert_eq!(reader.read(7), 0);
+        assert_eq!(reader.read(9), 0x1ff);
+        assert_eq!(reader.read(64), u64::MAX);
+
+        let mut reader = BitReader::new(&[0x7f]);
+        assert_eq!(reader.read(4), 0xf);
+        assert_eq!(reader.read(12), 0x007);
+
+        assert_eq!(BitReader::new(&[]).read(8), 0);
+    }
+
+    #[test]
+    fn read_header_consumes_only_the_header() {
+        const PKTS: [TracePkt; 3] = [
+            TracePkt::new_bits("four", 0x0f, 0x04, 4),
+            TracePkt::new_bits("two", 0x03, 0x00, 2),
+            TracePkt::new_bits("one", 0x01, 0x01, 1),
+        ];
+
+        /* 0b0100 then 0xa */
+        let buff = [0xa4];
+        let mut reader = BitReader::new(&buff);
+        assert_eq!(reader.read_header(&PKTS).map(|pkt| pkt.name), Some("four"));
+        assert_eq!(reader.read(4), 0xa);
+
+        /* 0b00 then 0b110011 */
+        let buff = [0xcc];
+        let mut reader = BitReader::new(&buff);
+        assert_eq!(reader.read_header(&PKTS).map(|pkt| pkt.name), Some("two"));
+        assert_eq!(reader.read(6), 0x33);
+
+        let buff = [0x02];
+        let mut reader = BitReader::new(&buff);
+        assert!(reader.read_header(&PKTS).is_none());
     }
 }
@@ -0,0 +1,521 @@
+use crate::etmv4::decode_continuation_field;
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_ptm::{PtmAtomType::*, PtmInstSet::*, *};
+
+const C_BIT: u8 = 0x80;
+
+pub fn get_ptm_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "async" => Some(decode_ptm_async),
+        "isync" => Some(decode_ptm_isync),
+        "atom" => Some(decode_ptm_atom),
+        "branch_address" => Some(decode_ptm_branch_address),
+        "waypoint_update" => Some(decode_ptm_waypoint_update),
+        "trigger" => Some(decode_ptm_trigger),
+        "context_id" => Some(decode_ptm_context_id),
+        "vmid" => Some(decode_ptm_vmid),
+        "timestamp" => Some(decode_ptm_timestamp),
+        "exception_return" => Some(decode_ptm_exception_return),
+        "ignore" => Some(decode_ptm_ignore),
+        _ => None,
+    }
+}
+
+/* read the cycle count which follows a packet in cycle-accurate mode */
+fn decode_ptm_cycle_count(
+    stream: &Stream,
+    offset: usize,
+) -> Result<(Option<u32>, usize), &'static str> {
+    if !stream.ptm_tracer.cycle_accurate {
+        return Ok((None, 0));
+    }
+    let (count, len) = decode_continuation_field(&stream.buff, offset, 5)
+        .ok_or("More than 5 bytes of cycle count")?;
+    Ok((Some(count), len))
+}
+
+/*
+ * Decode the compressed address of a branch address or waypoint update packet, which ETMv3
+ * shares with PTM. address and inst_set are the current state the address is compressed against.
+ * Bytes 0 to 3 carry 6, 7, 7 and 7 address bits and a continuation bit each. If the packet
+ * stops before byte 4, bit[6] of its last byte flags the information which follows and
+ * it carries only 6 address bits. Byte 4 carries the flag, the instruction set and the rest
+ * of the address. ARM addresses are word-aligned and Jazelle ones byte-aligned, so the
+ * address bits are shifted according to the instruction set.
+ * Return the address, the instruction set, the flag and the number of bytes.
+ */
+pub fn decode_ptm_address(
+    buff: &[u8],
+    offset: usize,
+    address: u32,
+    inst_set: PtmInstSet,
+) -> Result<(u32, PtmInstSet, bool, usize), &'static str> {
+    let mut raw = 0;
+    let mut nr_bits = 0;
+    let mut follows = false;
+    let mut inst_set = inst_set;
+    let mut index = 0;
+
+    loop {
+        let data = *buff
+            .get(offset + index)
+            .ok_or("Truncated address in the packet")?;
+        index += 1;
+        if index == 1 {
+            raw |= ((data >> 1) & 0x3f) as u32;
+            nr_bits = 6;
+            if (data & C_BIT) == 0 {
+                break;
+            }
+        } else if index < 5 {
+            if (data & C_BIT) != 0 {
+                raw |= ((data & 0x7f) as u32) << nr_bits;
+                nr_bits += 7;
+            } else {
+                follows = (data & 0x40) != 0;
+                raw |= ((data & 0x3f) as u32) << nr_bits;
+                nr_bits += 6;
+                break;
+            }
+        } else {
+            follows = (data & 0x40) != 0;
+            if (data & 0x38) == 0x08 {
+                inst_set = Arm;
+                raw |= ((data & 0x07) as u32) << nr_bits;
+            } else if (data & 0x30) == 0x10 {
+                inst_set = Thumb;
+                raw |= ((data & 0x0f) as u32) << nr_bits;
+            } else if (data & 0x20) != 0 {
+                inst_set = Jazelle;
+                raw |= ((data & 0x1f) as u32) << nr_bits;
+            } else {
+                return Err("Reserved instruction set in the address");
+            }
+            nr_bits = 32;
+            break;
+        }
+    }
+
+    let shift = match inst_set {
+        Arm => 2,
+        Thumb => 1,
+        Jazelle => 0,
+    };
+    let address = if nr_bits == 32 {
+        raw << shift
+    } else {
+        let mask = ((1u32 << nr_bits) - 1) << shift;
+        (address & !mask) | ((raw << shift) & mask)
+    };
+
+    Ok((address, inst_set, follows, index))
+}
+
+pub fn decode_ptm_async(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    /* five bytes of 0x00 followed by 0x80 */
+    match stream.buff.get(pkt_offset..pkt_offset + 6) {
+        Some([0, 0, 0, 0, 0, 0x80]) => {
+            tracer_ptm_async(&stream.ptm_tracer);
+            Ok(6)
+        }
+        _ => Err("Invalid A-sync packet"),
+    }
+}
+
+pub fn decode_ptm_isync(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let mut index = 1;
+
+    let address = stream
+        .buff
+        .get(pkt_offset + index..pkt_offset + index + 4)
+        .ok_or("Truncated I-sync packet")?;
+    let address = u32::from_le_bytes([address[0], address[1], address[2], address[3]]);
+    index += 4;
+    /* bit[0] of the address is the Thumb state */
+    let inst_set = if (address & 1) != 0 { Thumb } else { Arm };
+    let info = *stream
+        .buff
+        .get(pkt_offset + index)
+        .ok_or("Truncated I-sync packet")?;
+    index += 1;
+
+    let (cycle_count, len) = decode_ptm_cycle_count(stream, pkt_offset + index)?;
+    index += len;
+
+    let size = stream.ptm_tracer.context_id_size as usize;
+    let mut context_id = None;
+    if size != 0 {
+        let bytes = stream
+            .buff
+            .get(pkt_offset + index..pkt_offset + index + size)
+            .ok_or("Truncated context ID in the I-sync packet")?;
+        context_id = Some(
+            bytes
+                .iter()
+                .enumerate()
+                .fold(0, |id, (i, &b)| id | ((b as u32) << (8 * i))),
+        );
+        index += size;
+    }
+
+    tracer_ptm_isync(
+        &mut stream.ptm_tracer,
+        address & !1,
+        inst_set,
+        info,
+        cycle_count,
+        context_id,
+        None,
+    );
+
+    Ok(index)
+}
+
+pub fn decode_ptm_atom(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let header = stream.buff[pkt_offset];
+    let mut index = 1;
+    /* bit[1] of the header: 1'b0 - E atom, 1'b1 - N atom */
+    let atom = if (header & 0x02) != 0 {
+        PtmAtomN
+    } else {
+        PtmAtomE
+    };
+    let mut cycle_count = None;
+
+    if stream.ptm_tracer.cycle_accurate {
+        /* bits[5:2] of the header are the low bits of the count, bit[6] the continuation */
+        let mut count = ((header >> 2) & 0x0f) as u32;
+        if (header & 0x40) != 0 {
+            let (high, len) = decode_continuation_field(&stream.buff, pkt_offset + index, 4)
+                .ok_or("More than 4 bytes of cycle count in the atom packet")?;
+            count |= high << 4;
+            index += len;
+        }
+        cycle_count = Some(count);
+    }
+
+    tracer_ptm_atom(&stream.ptm_tracer, atom, cycle_count);
+
+    Ok(index)
+}
+
+pub fn decode_ptm_branch_address(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (address, inst_set, has_exception, mut index) = decode_ptm_address(
+        &stream.buff,
+        pkt_offset,
+        stream.ptm_tracer.address,
+        stream.ptm_tracer.inst_set,
+    )?;
+    let mut exception = None;
+
+    if has_exception {
+        /* C | AltISA | Can | Exception[3:0] | NS, then Hyp | Exception[8:4] */
+        let data1 = *stream
+            .buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated exception information in the branch address packet")?;
+        index += 1;
+        let mut number = ((data1 >> 1) & 0x0f) as u16;
+        let mut hyp = false;
+        if (data1 & C_BIT) != 0 {
+            let data2 = *stream
+                .buff
+                .get(pkt_offset + index)
+                .ok_or("Truncated exception information in the branch address packet")?;
+            index += 1;
+            number |= ((data2 & 0x1f) as u16) << 4;
+            hyp = (data2 & 0x20) != 0;
+        }
+        exception = Some((number, (data1 & 0x01) != 0, hyp, (data1 & 0x20) != 0));
+    }
+
+    let (cycle_count, len) = decode_ptm_cycle_count(stream, pkt_offset + index)?;
+    index += len;
+
+    tracer_ptm_branch_address(
+        &mut stream.ptm_tracer,
+        address,
+        inst_set,
+        exception,
+        cycle_count,
+    );
+
+    Ok(index)
+}
+
+pub fn decode_ptm_waypoint_update(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let mut index = 1;
+    let (address, inst_set, has_alt_isa, len) = decode_ptm_address(
+        &stream.buff,
+        pkt_offset + index,
+        stream.ptm_tracer.address,
+        stream.ptm_tracer.inst_set,
+    )?;
+    index += len;
+    let mut alt_isa = None;
+
+    if has_alt_isa {
+        let data = *stream
+            .buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated AltISA information in the waypoint update packet")?;
+        index += 1;
+        alt_isa = Some((data & 0x40) != 0);
+    }
+
+    tracer_ptm_waypoint_update(&mut stream.ptm_tracer, address, inst_set, alt_isa);
+
+    Ok(index)
+}
+
+pub fn decode_ptm_trigger(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_ptm_trigger(&stream.ptm_tracer);
+    Ok(1)
+}
+
+pub fn decode_ptm_context_id(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let size = stream.ptm_tracer.context_id_size as usize;
+    let bytes = stream
+        .buff
+        .get(pkt_offset + 1..pkt_offset + 1 + size)
+        .ok_or("Truncated context ID packet")?;
+    let context_id = bytes
+        .iter()
+        .enumerate()
+        .fold(0, |id, (i, &b)| id | ((b as u32) << (8 * i)));
+
+    tracer_ptm_context_id(&mut stream.ptm_tracer, context_id);
+
+    Ok(1 + size)
+}
+
+pub fn decode_ptm_vmid(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let vmid = *stream
+        .buff
+        .get(pkt_offset + 1)
+        .ok_or("Truncated VMID packet")?;
+
+    tracer_ptm_vmid(&mut stream.ptm_tracer, vmid);
+
+    Ok(2)
+}
+
+/*
+ * Decode the timestamp packet payload, which ETMv3 shares with PTM.
+ * Return the timestamp, the number of its bits, the cycle count and the packet length.
+ */
+pub fn decode_ptm_timestamp_payload(
+    buff: &[u8],
+    pkt_offset: usize,
+) -> Result<(u64, u32, Option<u32>, usize), &'static str> {
+    let mut index = 1;
+    let mut ts = 0;
+    let mut nr_bits = 0;
+
+    /* up to 8 bytes of 7 bits with a continuation bit, then a last byte of 8 bits */
+    loop {
+        let data = *buff
+            .get(pkt_offset + index)
+            .ok_or("Truncated timestamp packet")?;
+        index += 1;
+        if index == 10 {
+            ts |= (data as u64) << nr_bits;
+            nr_bits += 8;
+            break;
+        }
+        ts |= ((data & !C_BIT) as u64) << nr_bits;
+        nr_bits += 7;
+        if (data & C_BIT) == 0 {
+            break;
+        }
+    }
+
+    /* bit[2] of the header: a cycle count follows */
+    let mut cycle_count = None;
+    if (buff[pkt_offset] & 0x04) != 0 {
+        let (count, len) = decode_continuation_field(buff, pkt_offset + index, 5)
+            .ok_or("More than 5 bytes of cycle count in the timestamp packet")?;
+        cycle_count = Some(count);
+        index += len;
+    }
+
+    Ok((ts, nr_bits, cycle_count, index))
+}
+
+pub fn decode_ptm_timestamp(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (ts, nr_bits, cycle_count, index) = decode_ptm_timestamp_payload(&stream.buff, pkt_offset)?;
+
+    tracer_ptm_timestamp(&mut stream.ptm_tracer, ts, nr_bits, cycle_count);
+
+    Ok(index)
+}
+
+pub fn decode_ptm_exception_return(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_ptm_exception_return(&stream.ptm_tracer);
+    Ok(1)
+}
+
+pub fn decode_ptm_ignore(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_ptm_ignore(&stream.ptm_tracer);
+    Ok(1)
+}
+
+pub const PTMPKTS: [TracePkt; 11] = [
+    TracePkt::new("async", 0xff, 0x00),
+    TracePkt::new("isync", 0xff, 0x08),
+    TracePkt::new("trigger", 0xff, 0x0c),
+    TracePkt::new("vmid", 0xff, 0x3c),
+    TracePkt::new("timestamp", 0xfb, 0x42),
+    TracePkt::new("ignore", 0xff, 0x66),
+    TracePkt::new("context_id", 0xff, 0x6e),
+    TracePkt::new("waypoint_update", 0xff, 0x72),
+    TracePkt::new("exception_return", 0xff, 0x76),
+    TracePkt::new("branch_address", 0x01, 0x01),
+    TracePkt::new("atom", 0x81, 0x80),
+];
+
+/* find the next A-sync packet, 5 bytes of 0x00 followed by 0x80, at or after from */
+pub fn ptm_find_async(buff: &[u8], from: usize) -> Option<usize> {
+    if from >= buff.len() {
+        return None;
+    }
+    buff[from..]
+        .windows(6)
+        .position(|w| w[..5].iter().all(|&b| b == 0) && w[5] == 0x80)
+        .map(|i| from + i)
+}
+
+pub fn ptm_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    let mut from = 0;
+    while let Some(i) = ptm_find_async(&stream.buff, from) {
+        /* an I-sync packet right after the A-sync gives the first address */
+        if stream.buff.get(i + 6) == Some(&0x08) {
+            /* SYNCING -> INSYNC */
+            stream.state = InSync;
+            return Ok(i);
+        }
+        from = i + 1;
+    }
+    Err("No I-sync packet right after an A-sync packet")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::State;
+
+    /* decode the packet at the start of buff, checking that it is the named one */
+    fn decode(stream: &mut Stream, buff: &[u8], name: &str) -> Result<usize, String> {
+        stream.buff = buff.to_vec();
+        let pkt = PTMPKTS
+            .iter()
+            .find(|pkt| (buff[0] & pkt.mask) == pkt.val)
+            .unwrap();
+        assert_eq!(pkt.name, name);
+        get_ptm_decode_func(pkt.name).unwrap()(0, stream).map_err(String::from)
+    }
+
+    #[test]
+    fn isync() {
+        let mut stream = Stream::new();
+        /* Thumb address 0x1000, tracing enabled in Non-secure state, then the context ID */
+        let buff = [0x08, 0x01, 0x10, 0x00, 0x00, 0x28, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(decode(&mut stream, &buff, "isync"), Ok(10));
+        assert_eq!(stream.ptm_tracer.address, 0x1000);
+        assert!(stream.ptm_tracer.inst_set == Thumb);
+        assert!(stream.ptm_tracer.ns);
+        assert_eq!(stream.ptm_tracer.context_id, 0x12345678);
+        assert!(decode(&mut stream, &buff[..9], "isync").is_err());
+
+        /* in cycle-accurate mode a cycle count comes before the context ID */
+        stream.ptm_tracer.cycle_accurate = true;
+        stream.ptm_tracer.context_id_size = 1;
+        let buff = [0x08, 0x00, 0x20, 0x00, 0x00, 0x20, 0x85, 0x01, 0x07];
+        assert_eq!(decode(&mut stream, &buff, "isync"), Ok(9));
+        assert_eq!(stream.ptm_tracer.address, 0x2000);
+        assert!(stream.ptm_tracer.inst_set == Arm);
+        assert_eq!(stream.ptm_tracer.context_id, 0x07);
+    }
+    #[test]
+    fn atom() {
+        let mut stream = Stream::new();
+        assert_eq!(decode(&mut stream, &[0x80], "atom"), Ok(1));
+        assert_eq!(decode(&mut stream, &[0xc6, 0x01], "atom"), Ok(1));
+
+        /* in cycle-accurate mode bit[6] of the header continues the count */
+        stream.ptm_tracer.cycle_accurate = true;
+        assert_eq!(decode(&mut stream, &[0xc6, 0x01], "atom"), Ok(2));
+        assert_eq!(decode(&mut stream, &[0x86], "atom"), Ok(1));
+    }
+    #[test]
+    fn branch_address() {
+        let mut stream = Stream::new();
+        /* a full ARM address in 5 bytes */
+        let buff = [0x81, 0x90, 0x80, 0x80, 0x0c];
+        assert_eq!(decode(&mut stream, &buff, "branch_address"), Ok(5));
+        assert_eq!(stream.ptm_tracer.address, 0x80001000);
+        assert!(stream.ptm_tracer.inst_set == Arm);
+
+        /* a single byte replaces address bits[7:2] */
+        assert_eq!(decode(&mut stream, &[0x21], "branch_address"), Ok(1));
+        assert_eq!(stream.ptm_tracer.address, 0x80001040);
+
+        /* bit[6] of the last address byte flags an IRQ taken in Non-secure state */
+        assert_eq!(
+            decode(&mut stream, &[0x83, 0x40, 0x1d], "branch_address"),
+            Ok(3)
+        );
+        assert_eq!(stream.ptm_tracer.address, 0x80000004);
+        assert!(stream.ptm_tracer.ns);
+
+        assert!(decode(
+            &mut stream,
+            &[0x81, 0x80, 0x80, 0x80, 0x00],
+            "branch_address"
+        )
+        .is_err());
+    }
+    #[test]
+    fn other_packets() {
+        let mut stream = Stream::new();
+        assert_eq!(decode(&mut stream, &[0x72, 0x21], "waypoint_update"), Ok(2));
+        assert_eq!(stream.ptm_tracer.address, 0x40);
+
+        let buff = [0x6e, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(decode(&mut stream, &buff, "context_id"), Ok(5));
+        assert_eq!(stream.ptm_tracer.context_id, 0x12345678);
+        assert_eq!(decode(&mut stream, &[0x3c, 0x05], "vmid"), Ok(2));
+        assert_eq!(stream.ptm_tracer.vmid, 5);
+
+        /* the timestamp replaces the low order bits it carries */
+        stream.ptm_tracer.timestamp = 0x12345;
+        assert_eq!(decode(&mut stream, &[0x42, 0x85, 0x01], "timestamp"), Ok(3));
+        assert_eq!(stream.ptm_tracer.timestamp, 0x10085);
+        assert_eq!(decode(&mut stream, &[0x46, 0x05, 0x03], "timestamp"), Ok(3));
+
+        assert_eq!(decode(&mut stream, &[0x0c], "trigger"), Ok(1));
+        assert_eq!(decode(&mut stream, &[0x76], "exception_return"), Ok(1));
+        assert_eq!(decode(&mut stream, &[0x66], "ignore"), Ok(1));
+        assert_eq!(
+            decode(&mut stream, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x80], "async"),
+            Ok(6)
+        );
+        assert!(decode(&mut stream, &[0x00, 0x00, 0x00, 0x00, 0x80], "async").is_err());
+    }
+    #[test]
+    fn synchronization() {
+        let mut stream = Stream::new();
+        stream.buff = vec![0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x08];
+        assert_eq!(ptm_find_async(&stream.buff, 0), Some(1));
+        assert_eq!(ptm_find_async(&stream.buff, 2), None);
+        assert_eq!(ptm_synchronization(&mut stream), Ok(1));
+        assert!(stream.state == State::InSync);
+
+        /* the A-sync must be followed by an I-sync */
+        let mut stream = Stream::new();
+        stream.buff = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x80];
+        assert!(ptm_synchronization(&mut stream).is_err());
+    }
+}
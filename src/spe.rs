@@ -0,0 +1,193 @@
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_spe::*;
+
+/*
+ * SPE records are sequences of packets ended by an End or a Timestamp packet. The size of the
+ * payload of a packet is 1 << bits[5:4] of its header. Address and counter packets may have an
+ * extended header, 0b001000II followed by the header, with bits[4:3] of their index in II.
+ */
+
+pub fn get_spe_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "padding" => Some(decode_spe_padding),
+        "end" => Some(decode_spe_end),
+        "timestamp" => Some(decode_spe_timestamp),
+        "extended" | "address" | "counter" => Some(decode_spe_indexed),
+        "events" => Some(decode_spe_events),
+        "data_source" => Some(decode_spe_data_source),
+        "context" => Some(decode_spe_context),
+        "op_type" => Some(decode_spe_op_type),
+        _ => None,
+    }
+}
+
+/* read the little-endian payload of the packet whose header is at offset */
+fn decode_spe_payload(stream: &Stream, offset: usize) -> Result<(u64, usize), &'static str> {
+    let size = 1 << ((stream.buff[offset] >> 4) & 0x3);
+    let payload = stream
+        .buff
+        .get(offset + 1..offset + 1 + size)
+        .ok_or("Truncated SPE packet")?;
+    Ok((
+        payload
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, &b)| value | ((b as u64) << (8 * i))),
+        size,
+    ))
+}
+
+pub fn decode_spe_padding(_: usize, _: &mut Stream) -> Result<usize, &str> {
+    Ok(1)
+}
+
+pub fn decode_spe_end(_: usize, stream: &mut Stream) -> Result<usize, &str> {
+    tracer_spe_end(&mut stream.spe_tracer);
+    Ok(1)
+}
+
+pub fn decode_spe_timestamp(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (timestamp, size) = decode_spe_payload(stream, pkt_offset)?;
+
+    tracer_spe_timestamp(&mut stream.spe_tracer, timestamp);
+
+    Ok(1 + size)
+}
+
+/*
+ * The index of an address or counter packet, the extended header 0b001000II gives bits[4:3]
+ * of it. Return the index and the offset of the header which follows any extended header.
+ */
+fn decode_spe_index(buff: &[u8], pkt_offset: usize) -> Result<(u8, usize), &'static str> {
+    let mut offset = pkt_offset;
+    let mut index = 0;
+
+    if (buff[offset] & 0xfc) == 0x20 {
+        index = (buff[offset] & 0x3) << 3;
+        offset += 1;
+    }
+    let header = *buff.get(offset).ok_or("Truncated SPE packet")?;
+    Ok((index | (header & 0x7), offset))
+}
+
+/* address packets 0b10110III and counter packets 0b10011III, with or without an extended header */
+pub fn decode_spe_indexed(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (index, offset) = decode_spe_index(&stream.buff, pkt_offset)?;
+    let header = stream.buff[offset];
+    let (payload, size) = decode_spe_payload(stream, offset)?;
+
+    match header & 0xf8 {
+        0xb0 => tracer_spe_address(&stream.spe_tracer, index, payload),
+        0x98 => tracer_spe_counter(&stream.spe_tracer, index, payload),
+        _ => return Err("Reserved header after an SPE extended header"),
+    }
+
+    Ok(offset - pkt_offset + 1 + size)
+}
+
+pub fn decode_spe_events(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (events, size) = decode_spe_payload(stream, pkt_offset)?;
+
+    tracer_spe_events(&stream.spe_tracer, events);
+
+    Ok(1 + size)
+}
+
+pub fn decode_spe_data_source(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (source, size) = decode_spe_payload(stream, pkt_offset)?;
+
+    tracer_spe_data_source(&stream.spe_tracer, source);
+
+    Ok(1 + size)
+}
+
+pub fn decode_spe_context(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let index = stream.buff[pkt_offset] & 0x3;
+    let (context, size) = decode_spe_payload(stream, pkt_offset)?;
+
+    tracer_spe_context(&stream.spe_tracer, index, context as u32);
+
+    Ok(1 + size)
+}
+
+pub fn decode_spe_op_type(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let class = stream.buff[pkt_offset] & 0x3;
+    let (payload, size) = decode_spe_payload(stream, pkt_offset)?;
+
+    tracer_spe_op_type(&stream.spe_tracer, class, payload as u8);
+
+    Ok(1 + size)
+}
+
+pub const SPEPKTS: [TracePkt; 10] = [
+    TracePkt::new("padding", 0xff, 0x00),
+    TracePkt::new("end", 0xff, 0x01),
+    TracePkt::new("timestamp", 0xff, 0x71),
+    TracePkt::new("extended", 0xfc, 0x20),
+    TracePkt::new("events", 0xcf, 0x42),
+    TracePkt::new("data_source", 0xcf, 0x43),
+    TracePkt::new("context", 0xfc, 0x64),
+    TracePkt::new("op_type", 0xfc, 0x48),
+    TracePkt::new("address", 0xf8, 0xb0),
+    TracePkt::new("counter", 0xf8, 0x98),
+];
+
+/* there is no synchronization packet in an SPE buffer, so a bad header ends the decode */
+pub fn spe_find_async(_: &[u8], _: usize) -> Option<usize> {
+    None
+}
+
+/* an SPE buffer starts with the first packet of a record */
+pub fn spe_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    /* SYNCING -> INSYNC */
+    stream.state = InSync;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* decode the packet at the offset as the stream decoder finds it */
+    fn decode_at(stream: &mut Stream, offset: usize) -> Result<usize, &str> {
+        let c = stream.buff[offset];
+        let pkt = SPEPKTS
+            .iter()
+            .find(|pkt| (c & pkt.mask) == pkt.val)
+            .unwrap();
+        get_spe_decode_func(pkt.name).unwrap()(offset, stream)
+    }
+
+    #[test]
+    fn index_of_extended_header() {
+        /* address index 2 */
+        assert_eq!(decode_spe_index(&[0xb2], 0), Ok((2, 0)));
+        /* an extended header with bits[4:3] = 0b01 before counter index 3 */
+        assert_eq!(decode_spe_index(&[0x21, 0x9b], 0), Ok((11, 1)));
+        assert_eq!(decode_spe_index(&[0x00, 0x23, 0xb7], 1), Ok((31, 2)));
+        assert!(decode_spe_index(&[0x21], 0).is_err());
+    }
+
+    #[test]
+    fn record_packets() {
+        let mut stream = Stream::new();
+        /* extended header and a PC address, a 2-byte counter, op_type, end */
+        stream.buff = vec![0x20, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0];
+        stream.buff.extend([0x99, 0x10, 0x00, 0x49, 0x00, 0x01]);
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(10));
+        assert_eq!(decode_at(&mut stream, 10), Ok(3));
+        assert_eq!(decode_at(&mut stream, 13), Ok(2));
+        assert_eq!(decode_at(&mut stream, 15), Ok(1));
+        assert_eq!(stream.spe_tracer.nr_records, 1);
+    }
+
+    #[test]
+    fn reserved_header_after_extended_header() {
+        let mut stream = Stream::new();
+        stream.buff = vec![0x20, 0x01, 0x00];
+        assert!(decode_at(&mut stream, 0).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::stream::State::InSync;
+use crate::stream::Stream;
+use crate::tracer_stpv2::*;
+
+/*
+ * STPv2 is nibble-oriented, so an STPv2 stream is decoded from a buffer which holds one nibble
+ * per byte and all offsets count nibbles. Opcodes are 1 to 3 nibbles long. Packets are found
+ * from their first two nibbles, packed as a header byte with the first nibble in bits[7:4].
+ */
+
+/* the A-sync packet is 21 nibbles of 0xf followed by a nibble of 0x0 */
+const ASYNC_NR_F: usize = 21;
+
+pub fn get_stpv2_decode_func(pkt_name: &str) -> Option<DecodeFunc> {
+    match pkt_name {
+        "async" => Some(decode_stpv2_async),
+        "f0_op" => Some(decode_stpv2_f0_op),
+        "m8" | "m16" => Some(decode_stpv2_master),
+        "c8" | "c16" => Some(decode_stpv2_channel),
+        "merr" | "gerr" => Some(decode_stpv2_error),
+        "null" => Some(decode_stpv2_null),
+        "flag" | "flag_ts" => Some(decode_stpv2_flag),
+        "data" | "data_marker_ts" | "data_ts" | "data_marker" | "d4" | "d4_marker_ts" | "d4_ts"
+        | "d4_marker" => Some(decode_stpv2_data),
+        _ => None,
+    }
+}
+
+/* split every byte into two nibbles, the low order nibble first */
+pub fn stpv2_nibbles(buff: &[u8]) -> Vec<u8> {
+    buff.iter().flat_map(|&b| [b & 0x0f, b >> 4]).collect()
+}
+
+pub fn stpv2_header(stream: &Stream, offset: usize) -> u8 {
+    let next = stream.buff.get(offset + 1).copied().unwrap_or(0);
+    (stream.buff[offset] << 4) | next
+}
+
+/* read a field of nr_nibbles nibbles, the most significant nibble first */
+fn decode_stpv2_field(
+    stream: &Stream,
+    offset: usize,
+    nr_nibbles: usize,
+) -> Result<u64, &'static str> {
+    let nibbles = stream
+        .buff
+        .get(offset..offset + nr_nibbles)
+        .ok_or("Truncated STPv2 packet")?;
+    Ok(nibbles
+        .iter()
+        .fold(0, |value, &nibble| (value << 4) | nibble as u64))
+}
+
+/*
+ * Read the timestamp field which ends a packet: a nibble giving the number of nibbles
+ * of the timestamp, with 0xd meaning 14 and 0xe meaning 16, and then the timestamp itself.
+ */
+fn decode_stpv2_timestamp(
+    stream: &Stream,
+    offset: usize,
+) -> Result<(Stpv2Timestamp, usize), &'static str> {
+    let nr_nibbles = match decode_stpv2_field(stream, offset, 1)? {
+        0xd => 14,
+        0xe => 16,
+        0xf => return Err("Reserved length of an STPv2 timestamp"),
+        n => n as usize,
+    };
+    let value = decode_stpv2_field(stream, offset + 1, nr_nibbles)?;
+    Ok((
+        Stpv2Timestamp {
+            value,
+            nr_bits: (nr_nibbles * 4) as u32,
+        },
+        1 + nr_nibbles,
+    ))
+}
+
+pub fn decode_stpv2_async(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let nr_f = stream.buff[pkt_offset..]
+        .iter()
+        .take_while(|&&nibble| nibble == 0xf)
+        .count();
+
+    if nr_f < ASYNC_NR_F || stream.buff.get(pkt_offset + nr_f) != Some(&0x0) {
+        return Err("Invalid ASYNC packet");
+    }
+
+    tracer_stpv2_async(&mut stream.stpv2_tracer);
+
+    Ok(nr_f + 1)
+}
+
+/* the 3-nibble opcodes 0xf0n */
+pub fn decode_stpv2_f0_op(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let op = *stream
+        .buff
+        .get(pkt_offset + 2)
+        .ok_or("Truncated STPv2 packet")?;
+    let mut index = 3;
+    let mut ts = None;
+
+    /* the odd opcodes of each pair are the timestamped ones */
+    let timestamped = (op & 1) != 0;
+    let payload = match op {
+        0x0 => {
+            let version = decode_stpv2_field(stream, pkt_offset + index, 1)? as u8;
+            tracer_stpv2_version(&mut stream.stpv2_tracer, version);
+            return Ok(index + 1);
+        }
+        0x1 => 0,
+        0x6 | 0x7 => 2,
+        0x8 | 0x9 => 8,
+        _ => return Err("Reserved STPv2 opcode"),
+    };
+    let data = decode_stpv2_field(stream, pkt_offset + index, payload)?;
+    index += payload;
+    if timestamped {
+        let (timestamp, len) = decode_stpv2_timestamp(stream, pkt_offset + index)?;
+        ts = Some(timestamp);
+        index += len;
+    }
+
+    match op {
+        0x1 => tracer_stpv2_null(&mut stream.stpv2_tracer, ts),
+        0x6 | 0x7 => tracer_stpv2_trigger(&mut stream.stpv2_tracer, data as u8, ts),
+        _ => tracer_stpv2_frequency(&mut stream.stpv2_tracer, data as u32, ts),
+    }
+
+    Ok(index)
+}
+
+/* M8 0x1 and M16 0xf1 */
+pub fn decode_stpv2_master(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (index, nr_nibbles) = if stream.buff[pkt_offset] == 0xf {
+        (2, 4)
+    } else {
+        (1, 2)
+    };
+    let master = decode_stpv2_field(stream, pkt_offset + index, nr_nibbles)? as u16;
+
+    tracer_stpv2_master(&mut stream.stpv2_tracer, master);
+
+    Ok(index + nr_nibbles)
+}
+
+/* C8 0x3 replaces bits[7:0] of the channel and C16 0xf3 all of it */
+pub fn decode_stpv2_channel(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (index, nr_nibbles) = if stream.buff[pkt_offset] == 0xf {
+        (2, 4)
+    } else {
+        (1, 2)
+    };
+    let channel = decode_stpv2_field(stream, pkt_offset + index, nr_nibbles)? as u16;
+
+    tracer_stpv2_channel(&mut stream.stpv2_tracer, channel, (nr_nibbles * 4) as u32);
+
+    Ok(index + nr_nibbles)
+}
+
+/* MERR 0x2 and GERR 0xf2 */
+pub fn decode_stpv2_error(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let global = stream.buff[pkt_offset] == 0xf;
+    let index = if global { 2 } else { 1 };
+    let error = decode_stpv2_field(stream, pkt_offset + index, 2)? as u8;
+
+    tracer_stpv2_error(&stream.stpv2_tracer, global, error);
+
+    Ok(index + 2)
+}
+
+pub fn decode_stpv2_null(_: usize, _: &mut Stream) -> Result<usize, &str> {
+    Ok(1)
+}
+
+/* FLAG_TS 0xe and FLAG 0xfe */
+pub fn decode_stpv2_flag(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    if stream.buff[pkt_offset] == 0xf {
+        tracer_stpv2_flag(&mut stream.stpv2_tracer, None);
+        return Ok(2);
+    }
+
+    let (ts, len) = decode_stpv2_timestamp(stream, pkt_offset + 1)?;
+    tracer_stpv2_flag(&mut stream.stpv2_tracer, Some(ts));
+
+    Ok(1 + len)
+}
+
+/*
+ * Data packets. The 1-nibble opcodes are D8 to D64 (0x4 to 0x7), D8MTS to D64MTS (0x8 to 0xb),
+ * D4 (0xc) and D4MTS (0xd). The 2-nibble ones are D8TS to D64TS (0xf4 to 0xf7), D8M to D64M
+ * (0xf8 to 0xfb), D4TS (0xfc) and D4M (0xfd).
+ */
+pub fn decode_stpv2_data(pkt_offset: usize, stream: &mut Stream) -> Result<usize, &str> {
+    let (op, mut index) = if stream.buff[pkt_offset] == 0xf {
+        (stream.buff[pkt_offset + 1], 2)
+    } else {
+        (stream.buff[pkt_offset], 1)
+    };
+    let (marker, timestamped) = match (index, op) {
+        (1, 0x4..=0x7) | (1, 0xc) => (false, false),
+        (1, _) => (true, true),
+        (_, 0x4..=0x7) | (_, 0xc) => (false, true),
+        _ => (true, false),
+    };
+    let nr_nibbles = if op >= 0xc { 1 } else { 2 << (op & 3) };
+
+    let data = decode_stpv2_field(stream, pkt_offset + index, nr_nibbles)?;
+    index += nr_nibbles;
+    let mut ts = None;
+    if timestamped {
+        let (timestamp, len) = decode_stpv2_timestamp(stream, pkt_offset + index)?;
+        ts = Some(timestamp);
+        index += len;
+    }
+
+    tracer_stpv2_data(
+        &mut stream.stpv2_tracer,
+        (nr_nibbles * 4) as u32,
+        data,
+        marker,
+        ts,
+    );
+
+    Ok(index)
+}
+
+pub const STPV2PKTS: [TracePkt; 19] = [
+    TracePkt::new("async", 0xff, 0xff),
+    TracePkt::new("f0_op", 0xff, 0xf0),
+    TracePkt::new("m16", 0xff, 0xf1),
+    TracePkt::new("gerr", 0xff, 0xf2),
+    TracePkt::new("c16", 0xff, 0xf3),
+    TracePkt::new("data_ts", 0xfc, 0xf4),
+    TracePkt::new("data_marker", 0xfc, 0xf8),
+    TracePkt::new("d4_ts", 0xff, 0xfc),
+    TracePkt::new("d4_marker", 0xff, 0xfd),
+    TracePkt::new("flag", 0xff, 0xfe),
+    TracePkt::new("null", 0xf0, 0x00),
+    TracePkt::new("m8", 0xf0, 0x10),
+    TracePkt::new("merr", 0xf0, 0x20),
+    TracePkt::new("c8", 0xf0, 0x30),
+    TracePkt::new("data", 0xc0, 0x40),
+    TracePkt::new("data_marker_ts", 0xc0, 0x80),
+    TracePkt::new("d4", 0xf0, 0xc0),
+    TracePkt::new("d4_marker_ts", 0xf0, 0xd0),
+    TracePkt::new("flag_ts", 0xf0, 0xe0),
+];
+
+/* find the next A-sync packet in a nibble buffer at or after from */
+pub fn stpv2_find_async(buff: &[u8], from: usize) -> Option<usize> {
+    if from >= buff.len() {
+        return None;
+    }
+    buff[from..]
+        .windows(ASYNC_NR_F + 1)
+        .position(|w| w[..ASYNC_NR_F].iter().all(|&n| n == 0xf) && w[ASYNC_NR_F] == 0x0)
+        .map(|i| from + i)
+}
+
+pub fn stpv2_synchronization(stream: &mut Stream) -> Result<usize, &str> {
+    match stpv2_find_async(&stream.buff, 0) {
+        Some(i) => {
+            /* SYNCING -> INSYNC */
+            stream.state = InSync;
+            Ok(i)
+        }
+        None => Err("No ASYNC packet in the STPv2 stream"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* decode the packet at the offset of a nibble buffer as the stream decoder finds it */
+    fn decode_at(stream: &mut Stream, offset: usize) -> Result<usize, &str> {
+        let c = stpv2_header(stream, offset);
+        let pkt = STPV2PKTS
+            .iter()
+            .find(|pkt| (c & pkt.mask) == pkt.val)
+            .unwrap();
+        get_stpv2_decode_func(pkt.name).unwrap()(offset, stream)
+    }
+
+    #[test]
+    fn nibbles_low_order_first() {
+        assert_eq!(stpv2_nibbles(&[0x21, 0xf0]), vec![0x1, 0x2, 0x0, 0xf]);
+    }
+
+    #[test]
+    fn header_of_one_and_two_nibble_opcodes() {
+        let mut stream = Stream::new();
+        stream.buff = vec![0xf, 0x3, 0x1];
+        assert_eq!(stpv2_header(&stream, 0), 0xf3);
+        assert_eq!(stpv2_header(&stream, 1), 0x31);
+        /* the last nibble of the buffer is followed by nibbles of 0 */
+        assert_eq!(stpv2_header(&stream, 2), 0x10);
+    }
+
+    #[test]
+    fn async_and_version() {
+        let mut stream = Stream::new();
+        stream.buff = vec![0xf; ASYNC_NR_F];
+        /* ASYNC, then VERSION 4 (0xf00 0x4) */
+        stream.buff.extend([0x0, 0xf, 0x0, 0x0, 0x4]);
+
+        assert_eq!(stpv2_find_async(&stream.buff, 0), Some(0));
+        assert_eq!(decode_at(&mut stream, 0), Ok(ASYNC_NR_F + 1));
+        assert_eq!(decode_at(&mut stream, ASYNC_NR_F + 1), Ok(4));
+        assert_eq!(stream.stpv2_tracer.version, 4);
+        assert!(stream.stpv2_tracer.ts_gray);
+    }
+
+    #[test]
+    fn master_and_channel() {
+        let mut stream = Stream::new();
+        /* M16 0x1234, C16 0xabcd, then C8 0x56 replaces bits[7:0] of the channel */
+        stream.buff = vec![
+            0xf, 0x1, 0x1, 0x2, 0x3, 0x4, 0xf, 0x3, 0xa, 0xb, 0xc, 0xd, 0x3, 0x5, 0x6,
+        ];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(6));
+        assert_eq!(stream.stpv2_tracer.master, 0x1234);
+        assert_eq!(decode_at(&mut stream, 6), Ok(6));
+        assert_eq!(stream.stpv2_tracer.channel, 0xabcd);
+        assert_eq!(decode_at(&mut stream, 12), Ok(3));
+        assert_eq!(stream.stpv2_tracer.channel, 0xab56);
+    }
+
+    #[test]
+    fn data_with_timestamp() {
+        let mut stream = Stream::new();
+        stream.stpv2_tracer.timestamp = 0x1ff;
+        /* D16TS 0xbeef with a 2-nibble timestamp 0x42, which replaces bits[7:0] */
+        stream.buff = vec![0xf, 0x5, 0xb, 0xe, 0xe, 0xf, 0x2, 0x4, 0x2];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(9));
+        assert_eq!(stream.stpv2_tracer.timestamp, 0x142);
+    }
+
+    #[test]
+    fn marked_data_with_timestamp() {
+        let mut stream = Stream::new();
+        /* D8MTS 0x5a with a 1-nibble timestamp 0x7 */
+        stream.buff = vec![0x8, 0x5, 0xa, 0x1, 0x7];
+
+        assert_eq!(decode_at(&mut stream, 0), Ok(5));
+        assert_eq!(stream.stpv2_tracer.timestamp, 0x7);
+    }
+
+    #[test]
+    fn truncated_and_reserved_packets() {
+        let mut stream = Stream::new();
+        /* D32 with only 3 of its 8 nibbles */
+        stream.buff = vec![0x6, 0x1, 0x2, 0x3];
+        assert!(decode_at(&mut stream, 0).is_err());
+
+        /* 0xf02 is a reserved opcode */
+        stream.buff = vec![0xf, 0x0, 0x2];
+        assert!(decode_at(&mut stream, 0).is_err());
+    }
+}
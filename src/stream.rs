@@ -1,11 +1,45 @@
+use crate::etmv3::*;
 use crate::etmv4::*;
+use crate::etrace::*;
+use crate::itm::*;
+use crate::pktproto::{DecodeFunc, TracePkt};
+use crate::ptm::*;
+use crate::spe::*;
+use crate::stpv2::*;
 use crate::stream::State::{Decoding, Reading, Syncing};
-use crate::tracer_etmv4::Etmv4Tracer;
+use crate::tracer_etmv4::{tracer_branch_records, Etmv4Tracer};
+use crate::tracer_etrace::EtraceTracer;
+use crate::tracer_itm::{tracer_itm_flush, ItmTracer};
+use crate::tracer_ptm::PtmTracer;
+use crate::tracer_spe::SpeTracer;
+use crate::tracer_stpv2::Stpv2Tracer;
+
+/* the trace protocols the decoder understands */
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Etmv4,
+    Ptm,
+    Etmv3,
+    Stpv2,
+    Itm,
+    Etrace,
+    Spe,
+}
 
 pub struct Stream {
     pub buff: Vec<u8>,
     pub state: State,
+    pub protocol: Protocol,
     pub tracer: Etmv4Tracer,
+    pub ptm_tracer: PtmTracer,
+    pub stpv2_tracer: Stpv2Tracer,
+    pub itm_tracer: ItmTracer,
+    pub etrace_tracer: EtraceTracer,
+    pub spe_tracer: SpeTracer,
+    /* trace IDs whose streams are not in the protocol of the stream */
+    pub trace_id_protocols: Vec<(u8, Protocol)>,
+    /* minor version of the ETMv4 architecture the trace unit implements */
+    pub etmv4_version: u8,
 }
 
 impl Stream {
@@ -13,7 +47,15 @@ impl Stream {
         Self {
             buff: Vec::new(),
             state: Reading,
+            protocol: Protocol::Etmv4,
             tracer: Etmv4Tracer::new(),
+            ptm_tracer: PtmTracer::new(),
+            stpv2_tracer: Stpv2Tracer::new(),
+            itm_tracer: ItmTracer::new(),
+            etrace_tracer: EtraceTracer::new(),
+            spe_tracer: SpeTracer::new(),
+            trace_id_protocols: Vec::new(),
+            etmv4_version: ETMV4_LATEST_VERSION,
         }
     }
 
@@ -21,7 +63,15 @@ impl Stream {
         Self {
             buff: Vec::with_capacity(self.buff.len()),
             state: Reading,
-            tracer: Etmv4Tracer::new(),
+            protocol: self.protocol,
+            tracer: self.tracer.init_new(),
+            ptm_tracer: self.ptm_tracer.init_new(),
+            stpv2_tracer: self.stpv2_tracer.init_new(),
+            itm_tracer: self.itm_tracer.init_new(),
+            etrace_tracer: self.etrace_tracer.init_new(),
+            spe_tracer: self.spe_tracer.init_new(),
+            trace_id_protocols: self.trace_id_protocols.clone(),
+            etmv4_version: self.etmv4_version,
         }
     }
 }
@@ -34,9 +84,106 @@ pub enum State {
     Decoding,
 }
 
+/* resynchronize at the next A-sync packet from the offset, which is skipped if it is not one */
+fn skip_to_async(
+    stream: &Stream,
+    from: usize,
+    find_async: fn(&[u8], usize) -> Option<usize>,
+) -> Option<usize> {
+    match find_async(&stream.buff, from) {
+        Some(i) => {
+            if i != from {
+                println!("Skipped {} bytes of trace before the next A-sync", i - from);
+            }
+            Some(i)
+        }
+        None => {
+            println!("No A-sync packet in the rest of the trace stream");
+            None
+        }
+    }
+}
+
 pub fn decode_stream(stream: &mut Stream) {
     let mut cur;
 
+    match stream.protocol {
+        Protocol::Ptm => {
+            decode_byte_stream(
+                stream,
+                "PTM",
+                &PTMPKTS,
+                byte_header,
+                get_ptm_decode_func,
+                ptm_synchronization,
+                ptm_find_async,
+            );
+            return;
+        }
+        Protocol::Etmv3 => {
+            decode_byte_stream(
+                stream,
+                "ETMv3",
+                &ETMV3PKTS,
+                byte_header,
+                get_etmv3_decode_func,
+                etmv3_synchronization,
+                ptm_find_async,
+            );
+            return;
+        }
+        Protocol::Stpv2 => {
+            stream.buff = stpv2_nibbles(&stream.buff);
+            decode_byte_stream(
+                stream,
+                "STPv2",
+                &STPV2PKTS,
+                stpv2_header,
+                get_stpv2_decode_func,
+                stpv2_synchronization,
+                stpv2_find_async,
+            );
+            return;
+        }
+        Protocol::Itm => {
+            decode_byte_stream(
+                stream,
+                "ITM",
+                &ITMPKTS,
+                byte_header,
+                get_itm_decode_func,
+                itm_synchronization,
+                itm_find_async,
+            );
+            return;
+        }
+        Protocol::Etrace => {
+            decode_byte_stream(
+                stream,
+                "RISC-V E-Trace",
+                &ETRACE_ENCAP_PKTS,
+                byte_header,
+                get_etrace_decode_func,
+                etrace_synchronization,
+                etrace_find_async,
+            );
+            return;
+        }
+        Protocol::Spe => {
+            decode_byte_stream(
+                stream,
+                "SPE",
+                &SPEPKTS,
+                byte_header,
+                get_spe_decode_func,
+                spe_synchronization,
+                spe_find_async,
+            );
+            return;
+        }
+        Protocol::Etmv4 => {}
+    }
+
     if stream.state == Reading {
         /* READING -> SYNCING */
         stream.state = Syncing;
@@ -61,25 +208,32 @@ pub fn decode_stream(stream: &mut Stream) {
     let mut c;
     while cur < stream.buff.len() {
         c = stream.buff[cur];
-        let mut packet = None;
-        for tracepkt in &TRACEPKTS {
-            if (c & tracepkt.mask) == tracepkt.val {
-                packet = Some(tracepkt);
-                break;
-            }
-        }
+        let packet = etmv4_find_packet(c, stream);
         if let None = packet {
-            eprintln!("Cannot recognize a packet header 0x{:02x}", c);
-            eprintln!("Proceed on guesswork");
-            cur += 1;
+            /* a reserved header, nothing after it can be trusted until the next A-sync */
+            eprintln!(
+                "Reserved packet header 0x{:02x} at offset {} in {}",
+                c,
+                cur,
+                etmv4_arch_name(stream)
+            );
+            match skip_to_async(stream, cur + 1, etmv4_find_async) {
+                Some(i) => cur = i,
+                None => break,
+            }
             continue;
         }
-        if cur == 79008 {
-            println!("123");
-        }
         match get_decode_func(packet.unwrap().name).unwrap()(cur, stream) {
             Ok(i) => {
                 cur += i;
+                if stream.state == Syncing {
+                    /* after an overflow, decode again from the A-sync and TraceInfo which follow */
+                    match skip_to_async(stream, cur, etmv4_find_async) {
+                        Some(i) => cur = i,
+                        None => break,
+                    }
+                    stream.state = Decoding;
+                }
             }
             Err(msg) => {
                 eprintln!("{}", msg);
@@ -88,10 +242,95 @@ pub fn decode_stream(stream: &mut Stream) {
                     packet.unwrap().name,
                     cur
                 );
-                eprintln!("Proceed on guesswork");
-                cur += 1;
+                match skip_to_async(stream, cur + 1, etmv4_find_async) {
+                    Some(i) => cur = i,
+                    None => break,
+                }
             }
         }
     }
+    if stream.tracer.show_branch_records {
+        tracer_branch_records(&stream.tracer);
+    }
+    println!("Complete decode of the trace stream");
+}
+
+fn byte_header(stream: &Stream, offset: usize) -> u8 {
+    stream.buff[offset]
+}
+
+/*
+ * Decode a PTM, ETMv3, STPv2, ITM, E-Trace or SPE stream, where every packet is found from its header byte alone.
+ * header reads the header byte at an offset.
+ */
+fn decode_byte_stream(
+    stream: &mut Stream,
+    arch_name: &str,
+    pkts: &[TracePkt],
+    header: fn(&Stream, usize) -> u8,
+    get_func: fn(&str) -> Option<DecodeFunc>,
+    synchronization: fn(&mut Stream) -> Result<usize, &str>,
+    find_async: fn(&[u8], usize) -> Option<usize>,
+) {
+    let mut cur;
+
+    if stream.state == Reading {
+        /* READING -> SYNCING */
+        stream.state = Syncing;
+    } else {
+        eprintln!("Stream state is not correct");
+        return;
+    }
+
+    println!("Syncing the trace stream...");
+    cur = match synchronization(stream) {
+        Ok(i) => i,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("Cannot find any synchronization packet");
+            return;
+        }
+    };
+    println!("Decoding the trace stream...");
+
+    /* INSYNC -> DECODING */
+    stream.state = Decoding;
+    while cur < stream.buff.len() {
+        let c = header(stream, cur);
+        let packet = pkts.iter().find(|pkt| (c & pkt.mask) == pkt.val);
+        let packet = match packet {
+            Some(packet) => packet,
+            None => {
+                eprintln!(
+                    "Reserved packet header 0x{:02x} at offset {} in {}",
+                    c, cur, arch_name
+                );
+                match skip_to_async(stream, cur + 1, find_async) {
+                    Some(i) => cur = i,
+                    None => break,
+                }
+                continue;
+            }
+        };
+        match get_func(packet.name).unwrap()(cur, stream) {
+            Ok(i) => {
+                cur += i;
+            }
+            Err(msg) => {
+                eprintln!("{}", msg);
+                eprintln!(
+                    "Cannot decode a packet of type {} at offset {}",
+                    packet.name, cur
+                );
+                match skip_to_async(stream, cur + 1, find_async) {
+                    Some(i) => cur = i,
+                    None => break,
+                }
+            }
+        }
+    }
+    if stream.protocol == Protocol::Itm {
+        tracer_itm_flush(&mut stream.itm_tracer);
+    }
     println!("Complete decode of the trace stream");
 }
@@ -0,0 +1,19 @@
+use crate::tracer_ptm::PtmTracer;
+
+/*
+ * ETMv3 keeps its trace analyzer state in PtmTracer and reports the packets it shares with PTM
+ * through the tracer_ptm functions. These are the packets which only ETMv3 has.
+ */
+
+pub fn tracer_etmv3_cycle_count(_: &PtmTracer, count: u32) {
+    println!("Cycle count - {} cycles", count);
+}
+
+/* in cycle-accurate mode, a format 0 P-header is a cycle in which no instruction was executed */
+pub fn tracer_etmv3_wait(_: &PtmTracer, nr_w: u32) {
+    println!("Cycle - {} cycle(s) without an instruction", nr_w);
+}
+
+pub fn tracer_etmv3_exception_entry(_: &PtmTracer) {
+    println!("Exception entry");
+}
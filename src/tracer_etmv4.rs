@@ -1,5 +1,9 @@
+use std::collections::VecDeque;
+
+use crate::image::{A64Branch, Image};
 use crate::tracer_etmv4::AddrReg::*;
 use crate::tracer_etmv4::AtomType::*;
+use crate::tracer_etmv4::P0Element::*;
 
 const EXP_NAME: [Option<&str>; 16] = [
     Some("PE reset"),
@@ -20,6 +24,48 @@ const EXP_NAME: [Option<&str>; 16] = [
     Some("FIQ"),
 ];
 
+pub const CONDTYPE_PASS_FAIL: i32 = 0;
+pub const CONDTYPE_APSR: i32 = 1;
+
+/* exception types of ARMv6-M, ARMv7-M and ARMv8-M PEs, IRQ8 and above are 0x208 to 0x3EF */
+const M_EXP_NAME: [Option<&str>; 32] = [
+    None,
+    Some("PE reset"),
+    Some("NMI"),
+    Some("HardFault"),
+    Some("MemManage"),
+    Some("BusFault"),
+    Some("UsageFault"),
+    Some("SecureFault"),
+    None,
+    None,
+    None,
+    Some("SVCall"),
+    Some("DebugMonitor"),
+    None,
+    Some("PendSV"),
+    Some("SysTick"),
+    Some("IRQ0"),
+    Some("IRQ1"),
+    Some("IRQ2"),
+    Some("IRQ3"),
+    Some("IRQ4"),
+    Some("IRQ5"),
+    Some("IRQ6"),
+    Some("IRQ7"),
+    Some("Debug halt"),
+    Some("Lazy FP push"),
+    Some("Lockup"),
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+/* the architecture leaves the depth of the return stack to the implementation */
+const RETURN_STACK_DEPTH: usize = 16;
+
 #[derive(Clone, Copy, PartialEq, PartialOrd)]
 pub enum AddrReg {
     AddrRegIsUnknown,
@@ -27,11 +73,84 @@ pub enum AddrReg {
     AddrRegIs1,
 }
 
+#[derive(Clone, Copy)]
 pub enum AtomType {
     AtomTypeE,
     AtomTypeN,
 }
 
+/* P0 elements which are held in the speculative element queue until they are resolved */
+pub enum P0Element {
+    /* speculative before the trace stream was synchronized, so its type is unknown */
+    P0Unknown,
+    P0Atom(AtomType),
+    P0Exception {
+        tp: usize,
+        address: u64,
+    },
+    /* only a P0 element on M-profile PEs */
+    P0ExceptionReturn,
+    /* ARMv8-M: a function return to Non-secure state */
+    P0FunctionReturn,
+    /* a number of instructions whose branches are not traced, with the following address */
+    P0Q {
+        count: Option<u32>,
+        address: Option<AddressRegister>,
+    },
+    /* ETE with TME: the transaction elements, which are P0 elements if TRCIDR0.COMMTRANSP0 is set */
+    P0TransactionStart,
+    P0TransactionCommit,
+    /* the transaction failure exception, always a P0 element */
+    P0TransactionFailure,
+    /* ETE: a taken branch given by its own address rather than by an atom */
+    P0SourceAddress(AddressRegister),
+    /*
+     * the output of an element which is not a P0 element, printed in order with the P0 elements.
+     * flow tells that it is about the program flow after the P0 elements before it, e.g. an
+     * address, so it is canceled with them.
+     */
+    P0Text {
+        text: String,
+        flow: bool,
+    },
+}
+
+/* a taken branch, whose target is unknown if the trace does not give it */
+#[derive(Clone, Copy)]
+pub struct BranchRecord {
+    pub from: u64,
+    pub to: Option<u64>,
+}
+
+/* security states of the PE, Root and Realm are added by RME and signaled by the NSE bit of ETE */
+#[derive(Clone, Copy, PartialEq)]
+pub enum SecurityState {
+    Secure,
+    NonSecure,
+    Root,
+    Realm,
+}
+
+impl SecurityState {
+    pub fn from_nse_ns(nse: u8, ns: u8) -> Self {
+        match (nse, ns) {
+            (0, 0) => SecurityState::Secure,
+            (0, _) => SecurityState::NonSecure,
+            (_, 0) => SecurityState::Root,
+            _ => SecurityState::Realm,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SecurityState::Secure => "S",
+            SecurityState::NonSecure => "NS",
+            SecurityState::Root => "Root",
+            SecurityState::Realm => "Realm",
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct AddressRegister {
     pub address: u64,
@@ -47,7 +166,43 @@ impl AddressRegister {
     }
 }
 
+/*
+ * Values of the trace unit ID and configuration registers at the time the trace was captured.
+ * The default TRCIDR2 describes an 8-bit VMID and a 32-bit context ID.
+ */
+#[derive(Clone, Copy)]
+pub struct Etmv4Config {
+    pub trcidr0: u32,
+    pub trcidr2: u32,
+    pub trcidr8: u32,
+    pub trcidr9: u32,
+    pub trcidr10: u32,
+    pub trcidr11: u32,
+    pub trcidr12: u32,
+    pub trcidr13: u32,
+    pub trcconfigr: u32,
+}
+
+impl Etmv4Config {
+    pub fn new() -> Self {
+        Self {
+            trcidr0: 0,
+            trcidr2: (1 << 10) | (4 << 5),
+            trcidr8: 0,
+            trcidr9: 0,
+            trcidr10: 0,
+            trcidr11: 0,
+            trcidr12: 0,
+            trcidr13: 0,
+            trcconfigr: 0,
+        }
+    }
+}
+
 pub struct Etmv4Tracer {
+    /* the register values which the following configuration fields are derived from */
+    pub config: Etmv4Config,
+
     /* exactly the INFO field value in the TraceInfo packet */
     pub info: u32,
 
@@ -60,45 +215,294 @@ pub struct Etmv4Tracer {
     /* Trace analyzer state between receiving packets */
     pub timestamp: u64,
     pub address_register: [AddressRegister; 3],
+    /* TRCCONFIGR.RS: the targets of returns are left out of the trace and kept on a return stack */
+    pub return_stack_enabled: bool,
+    /* the most recently pushed return address at the back */
+    pub return_stack: VecDeque<AddressRegister>,
+    /* addresses and sizes of the branch with link instructions, which the trace does not mark */
+    pub link_branches: Vec<(u64, u64)>,
+    /* the program image, which tells the branch instruction each atom stands for */
+    pub image: Image,
+    /* the address of the next instruction, as far as the image and the trace tell */
+    pub pc: Option<u64>,
+    /* TRCCONFIGR.BB: every taken branch is followed by the address of its target */
+    pub branch_broadcast: bool,
+    /* taken branches whose target address has not been traced yet */
+    pub nr_unresolved_branches: u32,
+    /* the unresolved taken branch is a return, as the image tells */
+    pub unresolved_return: bool,
+    /* the PE is an M-profile one, with its own exception types and exception return */
+    pub m_profile: bool,
+    /* the trace unit is an ETE (Embedded Trace Extension) rather than an ETMv4 */
+    pub ete: bool,
+    /* TRCIDR0.COMMTRANSP0: transaction start and commit are P0 elements */
+    pub commtransp0: bool,
+    /* nesting depth of the transactions the PE is in, as of the committed P0 elements */
+    pub transaction_depth: u32,
+    /* P0 elements committed since the outermost transaction start */
+    pub nr_transaction_p0: u32,
+    /* the outermost transaction started before the most recent TraceInfo */
+    pub transaction_start_unknown: bool,
+    /* ETE: the source address of the most recent taken branch, waiting for its target */
+    pub pending_source: Option<u64>,
+    /* the number of source and target pairs found in the trace */
+    pub nr_branch_records: u64,
+    /* list the branch records as their targets are resolved */
+    pub show_branch_records: bool,
+    /* the address being traced belongs to an exception packet rather than a branch */
+    pub exception_address: bool,
     pub context_id: u32,
+    /* CONTEXTIDR_EL2, which is traced in place of the VMID when vmidopt is set */
+    pub context_id_el2: u32,
     // to be discussed: the following 4 fields uses bit field in C version
-    pub vmid: u8,
+    pub vmid: u32,
     pub ex_level: u8,
-    pub security: bool,
+    pub security: SecurityState,
     pub sixty_four_bit: bool,
-    pub curr_spec_depth: u32,
     pub p0_key: u32,
     pub cond_c_key: u32,
     pub cond_r_key: u32,
+    /* keys of the C elements which are waiting for their R elements */
+    pub cond_queue: VecDeque<u32>,
     pub p0_key_max: u32,
     pub cond_key_max_incr: u32,
     pub max_spec_depth: u32,
+    pub p1_key_max: u32,
+    pub p1_spc_key_max: u32,
+    /* sizes in bytes of the context ID and VMID fields in context packets */
+    pub context_id_size: u32,
+    pub vmid_size: u32,
+    /* the VMID field carries the virtual context ID (CONTEXTIDR_EL2) instead of the VMID */
+    pub vmidopt: bool,
     pub cc_threshold: u32,
+    /*
+     * uncommitted P0 elements, the oldest one at the front, and the transaction elements and
+     * output which are not P0 elements but must be retired in order with them
+     */
+    pub spec_queue: VecDeque<P0Element>,
+    /* print P0 elements when they arrive instead of when they are committed */
+    pub show_speculative: bool,
+    /* user-defined names of the 4 events, as programmed in TRCEVENTCTL0R/1R */
+    pub event_names: [Option<String>; 4],
+    /* number of the most recent numbered data synchronization marker */
+    pub data_sync_number: Option<u8>,
+    /* unnumbered data synchronization markers since the most recent numbered one */
+    pub nr_unnumbered_markers: u32,
+    /* number of P0 elements committed since the most recent Cycle Count element */
+    pub nr_p0_since_cc: u32,
 }
 
 impl Etmv4Tracer {
     pub fn new() -> Self {
         Self {
+            config: Etmv4Config::new(),
             info: 0,
             condtype: 0,
             commopt: 0,
             timestamp: 0,
             address_register: [AddressRegister::new(); 3],
+            return_stack_enabled: false,
+            return_stack: VecDeque::with_capacity(RETURN_STACK_DEPTH),
+            link_branches: Vec::new(),
+            image: Image::new(),
+            pc: None,
+            branch_broadcast: false,
+            nr_unresolved_branches: 0,
+            unresolved_return: false,
+            m_profile: false,
+            ete: false,
+            commtransp0: false,
+            transaction_depth: 0,
+            nr_transaction_p0: 0,
+            transaction_start_unknown: false,
+            pending_source: None,
+            nr_branch_records: 0,
+            show_branch_records: false,
+            exception_address: false,
             context_id: 0,
+            context_id_el2: 0,
             vmid: 0,
             ex_level: 0,
-            security: false,
+            security: SecurityState::NonSecure,
             sixty_four_bit: false,
-            curr_spec_depth: 0,
             p0_key: 0,
             cond_c_key: 0,
             cond_r_key: 0,
+            cond_queue: VecDeque::new(),
             p0_key_max: 0,
             cond_key_max_incr: 0,
             max_spec_depth: 0,
+            p1_key_max: 0,
+            p1_spc_key_max: 0,
+            context_id_size: 4,
+            vmid_size: 1,
+            vmidopt: false,
             cc_threshold: 0,
+            spec_queue: VecDeque::new(),
+            show_speculative: false,
+            event_names: Default::default(),
+            data_sync_number: None,
+            nr_unnumbered_markers: 0,
+            nr_p0_since_cc: 0,
+        }
+    }
+
+    /* create a tracer for another trace stream with the same configuration */
+    pub fn init_new(&self) -> Self {
+        let mut tracer = Self {
+            show_speculative: self.show_speculative,
+            event_names: self.event_names.clone(),
+            m_profile: self.m_profile,
+            ete: self.ete,
+            show_branch_records: self.show_branch_records,
+            link_branches: self.link_branches.clone(),
+            image: self.image.clone(),
+            ..Self::new()
+        };
+        /* the configuration has been validated when it was applied to self */
+        let _ = apply_config(&mut tracer, &self.config);
+        tracer
+    }
+}
+
+fn apply_config(tracer: &mut Etmv4Tracer, config: &Etmv4Config) -> Result<(), &'static str> {
+    let context_id_size = (config.trcidr2 >> 5) & 0x1f;
+    let vmid_size = (config.trcidr2 >> 10) & 0x1f;
+
+    if context_id_size != 0 && context_id_size != 4 {
+        return Err("Reserved CIDSIZE in TRCIDR2");
+    }
+    if vmid_size != 0 && vmid_size != 1 && vmid_size != 2 && vmid_size != 4 {
+        return Err("Reserved VMIDSIZE in TRCIDR2");
+    }
+
+    if tracer.ete {
+        /* ETE always traces 32-bit context IDs and 32-bit VMIDs, and has no data trace */
+        if context_id_size != 4 || vmid_size != 4 {
+            return Err("ETE requires 32-bit context IDs and VMIDs in TRCIDR2");
+        }
+        if (config.trcconfigr & (3 << 16)) != 0 {
+            return Err("ETE has no data trace, TRCCONFIGR.DA and TRCCONFIGR.DV must be zero");
         }
     }
+
+    tracer.config = *config;
+    tracer.condtype = ((config.trcidr0 >> 12) & 0x3) as i32;
+    tracer.commopt = ((config.trcidr0 >> 29) & 0x1) as i32;
+    tracer.commtransp0 = tracer.ete && (config.trcidr0 & (1 << 30)) != 0;
+    tracer.max_spec_depth = config.trcidr8;
+    tracer.p0_key_max = config.trcidr9;
+    tracer.p1_key_max = config.trcidr10;
+    tracer.p1_spc_key_max = config.trcidr11;
+    tracer.cond_key_max_incr = config.trcidr12.saturating_sub(config.trcidr13);
+    tracer.context_id_size = context_id_size;
+    tracer.vmid_size = vmid_size;
+    tracer.return_stack_enabled = (config.trcconfigr & (1 << 12)) != 0;
+    tracer.branch_broadcast = (config.trcconfigr & (1 << 3)) != 0;
+    /* TRCIDR2.VMIDOPT: 0b01 - selected by TRCCONFIGR.VMIDOPT, 0b10 - always CONTEXTIDR_EL2 */
+    tracer.vmidopt = match (config.trcidr2 >> 29) & 0x3 {
+        1 => (config.trcconfigr & (1 << 15)) != 0,
+        2 => true,
+        _ => false,
+    };
+
+    Ok(())
+}
+
+/* switch the tracer to ETE, starting from the TRCIDR2 value every ETE reports */
+pub fn tracer_enable_ete(tracer: &mut Etmv4Tracer) -> Result<(), &'static str> {
+    if tracer.m_profile {
+        return Err("ETE cannot trace an M-profile PE");
+    }
+    let config = Etmv4Config {
+        trcidr2: (4 << 10) | (4 << 5),
+        ..tracer.config
+    };
+    tracer.ete = true;
+    apply_config(tracer, &config)
+}
+
+/* derive the decoder configuration from the register values the trace was captured with */
+pub fn tracer_configure(
+    tracer: &mut Etmv4Tracer,
+    config: &Etmv4Config,
+) -> Result<(), &'static str> {
+    apply_config(tracer, config)?;
+
+    println!(
+        "Configuration - TRCIDR0 = 0x{:08X}, TRCIDR2 = 0x{:08X}, TRCCONFIGR = 0x{:08X},",
+        config.trcidr0, config.trcidr2, config.trcconfigr
+    );
+    println!(
+        "                TRCIDR8 = 0x{:X}, TRCIDR9 = 0x{:X}, TRCIDR10 = 0x{:X},",
+        config.trcidr8, config.trcidr9, config.trcidr10
+    );
+    println!(
+        "                TRCIDR11 = 0x{:X}, TRCIDR12 = 0x{:X}, TRCIDR13 = 0x{:X},",
+        config.trcidr11, config.trcidr12, config.trcidr13
+    );
+    println!(
+        "                max_spec_depth = {},",
+        tracer.max_spec_depth
+    );
+    println!("                p0_key_max = {},", tracer.p0_key_max);
+    println!(
+        "                p1_key_max = {}, p1_spc_key_max = {},",
+        tracer.p1_key_max, tracer.p1_spc_key_max
+    );
+    println!(
+        "                cond_key_max_incr = {},",
+        tracer.cond_key_max_incr
+    );
+    println!(
+        "                condtype = {},",
+        if tracer.condtype == CONDTYPE_APSR {
+            "APSR"
+        } else {
+            "PASS_FAIL"
+        }
+    );
+    println!("                commopt = {},", tracer.commopt);
+    println!(
+        "                branch broadcast {},",
+        if tracer.branch_broadcast {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    println!(
+        "                return stack {},",
+        if tracer.return_stack_enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    /*
+     * TRCCONFIGR.DA and TRCCONFIGR.DV. The data trace elements are sent in a stream of their own
+     * on another trace ID, which is not decoded.
+     */
+    println!(
+        "                data trace {},",
+        if (config.trcconfigr & (3 << 16)) != 0 {
+            "enabled, its trace stream is not decoded"
+        } else {
+            "disabled"
+        }
+    );
+    println!(
+        "                {}-bit context ID, {}-bit {}",
+        tracer.context_id_size * 8,
+        tracer.vmid_size * 8,
+        if tracer.vmidopt {
+            "virtual context ID (CONTEXTIDR_EL2)"
+        } else {
+            "VMID"
+        }
+    );
+
+    Ok(())
 }
 
 pub fn reset_address_register(tracer: &mut Etmv4Tracer) {
@@ -108,6 +512,43 @@ pub fn reset_address_register(tracer: &mut Etmv4Tracer) {
     tracer.address_register[1].is = AddrRegIsUnknown;
     tracer.address_register[2].address = 0;
     tracer.address_register[2].is = AddrRegIsUnknown;
+    tracer.return_stack.clear();
+    tracer.pc = None;
+    tracer.nr_unresolved_branches = 0;
+    tracer.unresolved_return = false;
+    tracer.pending_source = None;
+}
+
+/* push the return address of a taken branch with link onto the return stack */
+pub fn tracer_return_stack_push(tracer: &mut Etmv4Tracer, address: u64, is: AddrReg) {
+    if !tracer.return_stack_enabled {
+        return;
+    }
+    /* the oldest entry is lost when the return stack is full */
+    if tracer.return_stack.len() == RETURN_STACK_DEPTH {
+        tracer.return_stack.pop_front();
+    }
+    tracer
+        .return_stack
+        .push_back(AddressRegister { address, is });
+}
+
+/*
+ * Pop the target of a return which has no address packet, since the trace unit found it on its
+ * own return stack, and report it as a normal address element.
+ * Return false if the return stack is empty, so the branch is not a return this can resolve.
+ */
+pub fn tracer_return_stack_pop(tracer: &mut Etmv4Tracer) -> bool {
+    match tracer.return_stack.pop_back() {
+        Some(reg) => {
+            tracer.address_register[2] = tracer.address_register[1];
+            tracer.address_register[1] = tracer.address_register[0];
+            tracer.address_register[0] = reg;
+            tracer_address(tracer);
+            true
+        }
+        None => false,
+    }
 }
 
 pub fn tracer_trace_info(
@@ -122,8 +563,26 @@ pub fn tracer_trace_info(
 
     tracer.info = if (plctl & 1) != 0 { info } else { 0 };
     tracer.p0_key = if (plctl & 2) != 0 { key } else { 0 };
-    tracer.curr_spec_depth = if (plctl & 4) != 0 { spec } else { 0 };
     tracer.cc_threshold = if (plctl & 8) != 0 { cyct } else { 0 };
+    tracer.nr_p0_since_cc = 0;
+    tracer.cond_c_key = 0;
+    tracer.cond_r_key = 0;
+    tracer.cond_queue.clear();
+    /* ETE: TraceInfo in Transactional state, the transaction start is not in the trace */
+    if tracer.ete && (tracer.info & 0x40) != 0 {
+        tracer.transaction_depth = 1;
+        tracer.transaction_start_unknown = true;
+    } else {
+        tracer.transaction_depth = 0;
+        tracer.transaction_start_unknown = false;
+    }
+    tracer.nr_transaction_p0 = 0;
+    /* the elements which are speculative at the TraceInfo have not been traced */
+    let spec = if (plctl & 4) != 0 { spec } else { 0 };
+    tracer_drop_spec_queue(tracer);
+    for _ in 0..spec {
+        tracer.spec_queue.push_back(P0Unknown);
+    }
 
     println!(
         "TraceInfo - {},",
@@ -157,21 +616,96 @@ pub fn tracer_trace_info(
             "No explicit tracing of store instructions"
         }
     );
+    if tracer.ete {
+        println!(
+            "            {},",
+            if (tracer.info & 0x40) != 0 {
+                "In Transactional state"
+            } else {
+                "Not in Transactional state"
+            }
+        );
+    }
     println!("            p0_key = 0x{:X},", tracer.p0_key);
-    println!("            curr_spec_depth = {},", tracer.curr_spec_depth);
+    println!("            curr_spec_depth = {},", spec_depth(tracer));
     println!("            cc_threshold = 0x{:X}", tracer.cc_threshold);
 }
 
-pub fn tracer_trace_on(_: &Etmv4Tracer) {
+pub fn tracer_trace_on(tracer: &mut Etmv4Tracer) {
     println!("TraceOn - A discontinuity in the trace stream");
+    tracer.pc = None;
+    tracer.nr_unresolved_branches = 0;
+    tracer.unresolved_return = false;
+}
+
+pub fn tracer_discard(tracer: &mut Etmv4Tracer) {
+    /* all the uncommitted P0 elements are canceled */
+    let depth = spec_depth(tracer);
+    tracer_drop_spec_queue(tracer);
+    println!("Discard - {} speculative P0 element(s) discarded", depth);
+
+    tracer.cond_queue.clear();
+}
+
+pub fn tracer_overflow(tracer: &mut Etmv4Tracer) {
+    let depth = spec_depth(tracer);
+    tracer_drop_spec_queue(tracer);
+    println!("Overflow - Trace has been lost due to a trace unit buffer overflow,");
+    println!(
+        "           {} speculative P0 element(s) and {} C element(s) without result are invalidated,",
+        depth,
+        tracer.cond_queue.len()
+    );
+    println!("           waiting for the next TraceInfo");
+
+    /* nothing traced before the overflow can be resolved any more */
+    tracer.cond_queue.clear();
+    tracer.transaction_depth = 0;
+    tracer.nr_transaction_p0 = 0;
+    reset_address_register(tracer);
 }
 
-pub fn tracer_discard(_: &Etmv4Tracer) {
-    unimplemented!()
+pub fn tracer_instrumentation(_: &Etmv4Tracer, el: u8, value: u64) {
+    println!("Instrumentation - EL{}, value = 0x{:016X}", el, value);
 }
 
-pub fn tracer_overflow(_: &Etmv4Tracer) {
-    unimplemented!()
+pub fn tracer_timestamp_marker(_: &Etmv4Tracer) {
+    println!("Timestamp marker - the next Timestamp element applies to this point");
+}
+
+pub fn tracer_event(tracer: &mut Etmv4Tracer, events: u8) {
+    for i in 0..tracer.event_names.len() {
+        if (events & (1 << i)) == 0 {
+            continue;
+        }
+        let text = match &tracer.event_names[i] {
+            Some(name) => format!("Event - event {} ({})", i, name),
+            None => format!("Event - event {}", i),
+        };
+        tracer_print(tracer, text, false);
+    }
+}
+
+pub fn tracer_numbered_data_sync_marker(tracer: &mut Etmv4Tracer, number: u8) {
+    println!("Data sync marker - numbered marker {}", number);
+
+    tracer.data_sync_number = Some(number);
+    tracer.nr_unnumbered_markers = 0;
+}
+
+pub fn tracer_unnumbered_data_sync_marker(tracer: &mut Etmv4Tracer, a: u8) {
+    tracer.nr_unnumbered_markers += 1;
+
+    match tracer.data_sync_number {
+        Some(number) => println!(
+            "Data sync marker - unnumbered marker {}, {} since numbered marker {}",
+            a, tracer.nr_unnumbered_markers, number
+        ),
+        None => println!(
+            "Data sync marker - unnumbered marker {}, {} since the start of the trace",
+            a, tracer.nr_unnumbered_markers
+        ),
+    }
 }
 
 pub fn tracer_ts(
@@ -189,27 +723,330 @@ pub fn tracer_ts(
         tracer.timestamp |= timestamp;
     }
 
-    println!("Timestamp - {}", tracer.timestamp);
+    let text = format!("Timestamp - {}", tracer.timestamp);
+    tracer_print(tracer, text, false);
     if have_cc {
-        println!(
+        let text = format!(
             "            (number of cycles between the most recent Cycle Count element {})",
             count
         );
+        tracer_print(tracer, text, false);
     }
 }
 
 pub fn tracer_exception(tracer: &mut Etmv4Tracer, tp: usize) {
-    println!(
-        "Exception - exception type {}, address 0x{:016x}",
-        if tp < 32 && EXP_NAME[tp] != None {
-            EXP_NAME[tp].unwrap()
+    let address = tracer.address_register[0].address;
+
+    /* ETE has no conditional instruction tracing, and without C elements there is nothing to flush */
+    if !tracer.ete && !tracer.cond_queue.is_empty() {
+        tracer_cond_flush(tracer);
+    }
+    /* ETE uses exception type 0x18 for the failure of a transaction */
+    if tracer.ete && tp == 0x18 {
+        tracer_p0_element(tracer, P0TransactionFailure);
+    } else {
+        tracer_p0_element(tracer, P0Exception { tp, address });
+    }
+}
+
+/* the transaction elements are P0 elements only if TRCIDR0.COMMTRANSP0 is set */
+fn is_p0_element(elem: &P0Element, commtransp0: bool) -> bool {
+    match elem {
+        P0TransactionStart | P0TransactionCommit => commtransp0,
+        P0Text { .. } => false,
+        _ => true,
+    }
+}
+
+/* the number of speculative P0 elements */
+pub fn spec_depth(tracer: &Etmv4Tracer) -> usize {
+    tracer
+        .spec_queue
+        .iter()
+        .filter(|elem| is_p0_element(elem, tracer.commtransp0))
+        .count()
+}
+
+/*
+ * Print the output of an element which is not a P0 element. It is printed after the speculative
+ * P0 elements before it, so it waits behind them unless they are printed as they arrive.
+ */
+fn tracer_print(tracer: &mut Etmv4Tracer, text: String, flow: bool) {
+    if tracer.show_speculative || tracer.spec_queue.is_empty() {
+        println!("{}", text);
+    } else {
+        tracer.spec_queue.push_back(P0Text { text, flow });
+    }
+}
+
+/*
+ * Drop the speculative elements, which are not resolved any more. The output which is not about
+ * them is still printed.
+ */
+fn tracer_drop_spec_queue(tracer: &mut Etmv4Tracer) {
+    for elem in tracer.spec_queue.drain(..) {
+        if let P0Text { text, flow: false } = elem {
+            println!("{}", text);
+        }
+    }
+}
+
+/*
+ * A transaction element which is not a P0 element is retired after the P0 elements before it, so
+ * it waits behind them if they are speculative.
+ */
+fn tracer_transaction_element(tracer: &mut Etmv4Tracer, elem: P0Element) {
+    if tracer.commtransp0 {
+        tracer_p0_element(tracer, elem);
+    } else if tracer.spec_queue.is_empty() {
+        retire_p0_element(tracer, &elem);
+    } else {
+        if tracer.show_speculative {
+            print_p0_element(&elem, true, tracer.m_profile);
+        }
+        tracer.spec_queue.push_back(elem);
+    }
+}
+
+pub fn tracer_transaction_start(tracer: &mut Etmv4Tracer) {
+    tracer_transaction_element(tracer, P0TransactionStart);
+}
+
+pub fn tracer_transaction_commit(tracer: &mut Etmv4Tracer) {
+    tracer_transaction_element(tracer, P0TransactionCommit);
+}
+
+/*
+ * Keep track of the transactions as the elements are committed, and report the transaction
+ * elements, since what they mean depends on the transactions they are nested in.
+ */
+fn retire_p0_element(tracer: &mut Etmv4Tracer, elem: &P0Element) {
+    match elem {
+        P0TransactionStart => {
+            tracer.transaction_depth += 1;
+            if tracer.transaction_depth == 1 {
+                tracer.nr_transaction_p0 = 0;
+                tracer.transaction_start_unknown = false;
+            }
+            println!(
+                "Transaction start - nesting depth {}",
+                tracer.transaction_depth
+            );
+        }
+        P0TransactionCommit => {
+            if tracer.transaction_depth == 0 {
+                eprintln!("Transaction commit - the PE is not in a transaction");
+                return;
+            }
+            tracer.transaction_depth -= 1;
+            if tracer.transaction_depth == 0 {
+                println!(
+                    "Transaction commit - outermost transaction committed after {} P0 element(s)",
+                    tracer.nr_transaction_p0
+                );
+            } else {
+                println!(
+                    "Transaction commit - nesting depth {}",
+                    tracer.transaction_depth
+                );
+            }
+        }
+        P0TransactionFailure => {
+            println!("Transaction failure - all the nested transactions are aborted,");
+            if tracer.transaction_start_unknown {
+                println!(
+                    "                      {} P0 element(s) since the TraceInfo and the ones before it are rolled back",
+                    tracer.nr_transaction_p0
+                );
+            } else {
+                println!(
+                    "                      {} P0 element(s) since the outermost transaction start are rolled back",
+                    tracer.nr_transaction_p0
+                );
+            }
+            tracer.transaction_depth = 0;
+            tracer.nr_transaction_p0 = 0;
+            tracer.transaction_start_unknown = false;
+        }
+        P0Text { .. } => {}
+        _ => {
+            if tracer.transaction_depth != 0 {
+                tracer.nr_transaction_p0 += 1;
+            }
+        }
+    }
+}
+
+pub fn tracer_exception_return(tracer: &mut Etmv4Tracer) {
+    /* for ARMv6-M, ARMv7-M and ARMv8-M PEs, exception_return is a P0 element */
+    if tracer.m_profile {
+        tracer_p0_element(tracer, P0ExceptionReturn);
+    } else {
+        tracer_print(tracer, String::from("Exception return"), true);
+    }
+}
+
+pub fn tracer_function_return(tracer: &mut Etmv4Tracer) {
+    tracer_p0_element(tracer, P0FunctionReturn);
+}
+
+fn exception_name(tp: usize, m_profile: bool) -> String {
+    let name = if m_profile {
+        if (0x208..=0x3ef).contains(&tp) {
+            return format!("IRQ{}", tp - 0x200);
+        }
+        M_EXP_NAME.get(tp).copied().flatten()
+    } else {
+        EXP_NAME.get(tp).copied().flatten()
+    };
+
+    name.unwrap_or("Reserved").to_string()
+}
+
+fn print_p0_element(elem: &P0Element, speculative: bool, m_profile: bool) {
+    let suffix = if speculative { " (speculative)" } else { "" };
+
+    match elem {
+        P0Unknown => {}
+        P0Atom(AtomTypeE) => {
+            println!("ATOM - E{}", suffix);
+        }
+        P0Atom(AtomTypeN) => {
+            println!("ATOM - N{}", suffix);
+        }
+        P0Exception { tp, address } => {
+            println!(
+                "Exception - exception type {}, address 0x{:016x}{}",
+                exception_name(*tp, m_profile),
+                address,
+                suffix
+            );
+        }
+        P0ExceptionReturn => {
+            println!("Exception return{}", suffix);
+        }
+        P0FunctionReturn => {
+            println!("Function return{}", suffix);
+        }
+        /* the transaction elements are reported by retire_p0_element when they are committed */
+        P0TransactionStart if speculative => {
+            println!("Transaction start{}", suffix);
+        }
+        P0TransactionCommit if speculative => {
+            println!("Transaction commit{}", suffix);
+        }
+        P0TransactionFailure if speculative => {
+            println!("Transaction failure{}", suffix);
+        }
+        P0TransactionStart | P0TransactionCommit | P0TransactionFailure => {}
+        P0SourceAddress(reg) => {
+            println!(
+                "Source address - taken branch at 0x{:016x}{}",
+                reg.address, suffix
+            );
+        }
+        P0Q { count, address } => {
+            let count = match count {
+                Some(count) => format!("{}", count),
+                None => String::from("An unknown number of"),
+            };
+            match address {
+                Some(reg) => println!(
+                    "Q - {} instructions executed, then address 0x{:016x}{}",
+                    count, reg.address, suffix
+                ),
+                None => println!(
+                    "Q - {} instructions executed, address unknown{}",
+                    count, suffix
+                ),
+            }
+        }
+        P0Text { text, .. } => {
+            println!("{}", text);
+        }
+    }
+}
+
+/*
+ * Resolve the taken branch whose target has not been traced, before the next P0 element or
+ * address which is not its target. Without an address, the branch is a return whose target the
+ * trace unit found on its own return stack, if the return stack holds one.
+ * In branch broadcast mode every taken branch is followed by the address of its target, so a
+ * missing target is reported.
+ */
+pub fn tracer_resolve_branch(tracer: &mut Etmv4Tracer) {
+    if tracer.nr_unresolved_branches == 0 {
+        return;
+    }
+
+    tracer.unresolved_return = false;
+    if !(tracer.return_stack_enabled && tracer_return_stack_pop(tracer)) {
+        let text = if tracer.branch_broadcast {
+            "Branch broadcast - no target address for the preceding taken branch"
         } else {
-            "Reserved"
-        },
-        tracer.address_register[0].address
-    );
+            "Return stack - no target address for the preceding return"
+        };
+        tracer_print(tracer, String::from(text), true);
+    }
+    tracer.nr_unresolved_branches = 0;
+}
 
-    tracer_cond_flush(tracer);
+/*
+ * Follow an atom through the program image from the next instruction, if its address is known.
+ * The trace unit pushes the return address of a taken branch with link onto its return stack,
+ * and leaves the target of a taken return out of the trace if it is on the return stack.
+ */
+fn tracer_follow_atom(tracer: &mut Etmv4Tracer, tp: AtomType) {
+    let branch = match tracer.pc {
+        Some(pc) if tracer.sixty_four_bit => tracer.image.next_a64_branch(pc),
+        _ => None,
+    };
+    /* the target of an indirect branch is only known from the next address */
+    tracer.pc = None;
+
+    match (tp, branch) {
+        (_, None) => {}
+        (AtomTypeN, Some((address, _))) => {
+            tracer.pc = Some(address.wrapping_add(4));
+        }
+        (AtomTypeE, Some((address, A64Branch::Direct { target, link }))) => {
+            if link {
+                tracer_return_stack_push(tracer, address.wrapping_add(4), AddrRegIs0);
+            }
+            tracer.pc = Some(target);
+        }
+        (AtomTypeE, Some((address, A64Branch::Indirect { link }))) => {
+            if link {
+                tracer_return_stack_push(tracer, address.wrapping_add(4), AddrRegIs0);
+            }
+        }
+        (AtomTypeE, Some((_, A64Branch::Return))) => {
+            if tracer.return_stack_enabled {
+                tracer.nr_unresolved_branches = 1;
+                tracer.unresolved_return = true;
+            }
+        }
+    }
+}
+
+fn tracer_p0_element(tracer: &mut Etmv4Tracer, elem: P0Element) {
+    tracer_resolve_branch(tracer);
+    if tracer.branch_broadcast {
+        tracer.nr_unresolved_branches = match &elem {
+            P0Atom(AtomTypeE) | P0SourceAddress(_) => 1,
+            _ => 0,
+        };
+    }
+    match &elem {
+        P0Atom(tp) => tracer_follow_atom(tracer, *tp),
+        P0TransactionStart | P0TransactionCommit => {}
+        _ => tracer.pc = None,
+    }
+
+    if tracer.show_speculative {
+        print_p0_element(&elem, true, tracer.m_profile);
+    }
+    tracer.spec_queue.push_back(elem);
 
     /*
      * If p0_key_max is zero, it implies that the target CPU uses no P0 right-hand keys.
@@ -220,48 +1057,303 @@ pub fn tracer_exception(tracer: &mut Etmv4Tracer, tp: usize) {
         tracer.p0_key %= tracer.p0_key_max;
     }
 
-    tracer.curr_spec_depth += 1;
-    if tracer.max_spec_depth == 0 || (tracer.curr_spec_depth > tracer.max_spec_depth) {
+    if tracer.max_spec_depth == 0 || (spec_depth(tracer) > tracer.max_spec_depth as usize) {
         tracer_commit(tracer, 1);
     }
 }
 
-pub fn tracer_exception_return(_: &Etmv4Tracer) {
-    println!("Exception return");
-    /* FIXME: for ARMv6-M and ARMv7-M PEs, exception_return is a P0 element */
+/* the decoder resolves the previous taken branch before the source address is in the registers */
+pub fn tracer_source_address(tracer: &mut Etmv4Tracer) {
+    let reg = tracer.address_register[0];
+
+    /* the target of the previous branch is not traced, e.g. since it is a direct branch */
+    if let Some(from) = tracer.pending_source.replace(reg.address) {
+        tracer_branch_record(tracer, BranchRecord { from, to: None });
+    }
+    tracer_p0_element(tracer, P0SourceAddress(reg));
+
+    /*
+     * The trace does not tell which branches are branches with link, so the return stack is only
+     * pushed for the source addresses which are known to be ones.
+     */
+    let link = tracer
+        .link_branches
+        .iter()
+        .find(|(address, _)| *address == reg.address);
+    if let Some(&(address, size)) = link {
+        tracer_return_stack_push(tracer, address.wrapping_add(size), reg.is);
+    }
+}
+
+/* report a branch record once its target is resolved, rather than keeping all of them */
+fn tracer_branch_record(tracer: &mut Etmv4Tracer, record: BranchRecord) {
+    tracer.nr_branch_records += 1;
+    if !tracer.show_branch_records {
+        return;
+    }
+    let text = match record.to {
+        Some(to) => format!("Branch record - 0x{:016x} -> 0x{:016x}", record.from, to),
+        None => format!("Branch record - 0x{:016x} -> unknown", record.from),
+    };
+    tracer_print(tracer, text, true);
+}
+
+pub fn tracer_branch_records(tracer: &Etmv4Tracer) {
+    println!("Branch records - {} branch(es)", tracer.nr_branch_records);
+}
+
+pub fn tracer_q(tracer: &mut Etmv4Tracer, count: Option<u32>, has_address: bool) {
+    let address = if has_address {
+        Some(tracer.address_register[0])
+    } else {
+        None
+    };
+
+    tracer_p0_element(tracer, P0Q { count, address });
+}
+
+pub fn tracer_commit(tracer: &mut Etmv4Tracer, commit: u32) {
+    if tracer.show_speculative {
+        println!("Commit - {}", commit);
+    }
+
+    /* the elements up to the P0 element after the committed ones are retired */
+    let commtransp0 = tracer.commtransp0;
+    let mut nr_p0 = 0;
+    let nr_elem = tracer
+        .spec_queue
+        .iter()
+        .position(|elem| {
+            if is_p0_element(elem, commtransp0) {
+                nr_p0 += 1;
+            }
+            nr_p0 > commit
+        })
+        .unwrap_or(tracer.spec_queue.len());
+    retire_elements(tracer, nr_elem);
+    tracer.nr_p0_since_cc += commit;
+}
+
+/* print and retire the elements at the front of the speculative element queue */
+fn retire_elements(tracer: &mut Etmv4Tracer, nr_elem: usize) {
+    let elems: Vec<P0Element> = tracer.spec_queue.drain(..nr_elem).collect();
+    for elem in elems {
+        if !tracer.show_speculative {
+            print_p0_element(&elem, false, tracer.m_profile);
+        }
+        retire_p0_element(tracer, &elem);
+    }
+}
+
+pub fn tracer_cancel(tracer: &mut Etmv4Tracer, cancel: u32) {
+    if tracer.show_speculative {
+        println!("Cancel - {}", cancel);
+    }
+
+    /*
+     * The canceled P0 elements and the elements after them are removed, except the output which
+     * is not about the canceled program flow. What no speculative P0 element is left before is
+     * retired.
+     */
+    let mut nr_p0 = 0;
+    let mut kept = Vec::new();
+    while nr_p0 < cancel {
+        match tracer.spec_queue.pop_back() {
+            Some(elem) if is_p0_element(&elem, tracer.commtransp0) => nr_p0 += 1,
+            Some(elem @ P0Text { flow: false, .. }) => kept.push(elem),
+            Some(_) => {}
+            None => break,
+        }
+    }
+    tracer.spec_queue.extend(kept.into_iter().rev());
+    /* the image was followed along the canceled program flow */
+    tracer.pc = None;
+    let commtransp0 = tracer.commtransp0;
+    let nr_elem = tracer
+        .spec_queue
+        .iter()
+        .position(|elem| is_p0_element(elem, commtransp0))
+        .unwrap_or(tracer.spec_queue.len());
+    retire_elements(tracer, nr_elem);
+
+    /* the keys of the canceled P0 elements are reused by the following P0 elements */
+    if tracer.p0_key_max != 0 {
+        tracer.p0_key =
+            (tracer.p0_key + tracer.p0_key_max - cancel % tracer.p0_key_max) % tracer.p0_key_max;
+    }
+}
+
+pub fn tracer_mispredict(tracer: &mut Etmv4Tracer) {
+    if tracer.show_speculative {
+        println!("Mispredict");
+    }
+
+    /*
+     * The result of the most recent atom is inverted. Other P0 elements, e.g. exceptions, may
+     * have been traced after it.
+     */
+    tracer.pc = None;
+    let atom = tracer
+        .spec_queue
+        .iter_mut()
+        .rev()
+        .find_map(|elem| match elem {
+            P0Atom(tp) => Some(tp),
+            _ => None,
+        });
+    match atom {
+        Some(tp) => {
+            *tp = match tp {
+                AtomTypeE => AtomTypeN,
+                AtomTypeN => AtomTypeE,
+            };
+        }
+        None => {
+            eprintln!("Mispredict - there is no speculative atom");
+        }
+    }
+}
+
+pub fn tracer_cc(tracer: &mut Etmv4Tracer, unknown: bool, count: u32) {
+    let text = if unknown {
+        format!(
+            "Cycle count - unknown, applies to {} P0 element(s)",
+            tracer.nr_p0_since_cc
+        )
+    } else {
+        format!(
+            "Cycle count - {} cycles, applies to {} P0 element(s)",
+            count, tracer.nr_p0_since_cc
+        )
+    };
+    tracer_print(tracer, text, false);
+    tracer.nr_p0_since_cc = 0;
+}
+
+pub fn tracer_cond_inst(tracer: &mut Etmv4Tracer, key: u32) {
+    tracer.cond_c_key = key;
+    tracer.cond_queue.push_back(key);
 }
 
-fn tracer_commit(tracer: &mut Etmv4Tracer, commit: u32) {
-    println!("Commit - {}", commit);
-    tracer.curr_spec_depth -= commit;
+pub fn tracer_cond_result(tracer: &mut Etmv4Tracer, key: u32, result: u32) {
+    tracer.cond_r_key = key;
+
+    /* an R element resolves the oldest pending C element with the same key */
+    let matched = match tracer.cond_queue.iter().position(|&k| k == key) {
+        Some(pos) => {
+            tracer.cond_queue.remove(pos);
+            true
+        }
+        None => false,
+    };
+
+    let text = if tracer.condtype == CONDTYPE_APSR {
+        format!(
+            "Conditional result - key 0x{:X}, APSR N = {}, Z = {}, C = {}, V = {}{}",
+            key,
+            (result >> 3) & 1,
+            (result >> 2) & 1,
+            (result >> 1) & 1,
+            result & 1,
+            if matched {
+                ""
+            } else {
+                " (no matching C element)"
+            }
+        )
+    } else {
+        format!(
+            "Conditional result - key 0x{:X}, instruction {}{}",
+            key,
+            if (result & 1) != 0 {
+                "passed"
+            } else {
+                "failed"
+            },
+            if matched {
+                ""
+            } else {
+                " (no matching C element)"
+            }
+        )
+    };
+    tracer_print(tracer, text, false);
 }
 
-fn tracer_cond_flush(_: &Etmv4Tracer) {
-    println!("Conditional flush");
+pub fn tracer_cond_flush(tracer: &mut Etmv4Tracer) {
+    let text = format!(
+        "Conditional flush - {} C element(s) without result discarded",
+        tracer.cond_queue.len()
+    );
+    tracer_print(tracer, text, false);
+    tracer.cond_queue.clear();
 }
 
-pub fn tracer_address(tracer: &Etmv4Tracer) {
+pub fn tracer_address(tracer: &mut Etmv4Tracer) {
     let address = tracer.address_register[0].address;
     let is = tracer.address_register[0].is;
 
-    if tracer.sixty_four_bit {
-        println!(
+    let text = if tracer.sixty_four_bit {
+        format!(
             "Address - Instruction address 0x{:016x}, Instruction set Aarch64",
             address,
-        );
+        )
     } else {
         if is != AddrRegIs0 {
-            println!(
+            format!(
                 "Address - Instruction address 0x{:016x}, Instruction set Aarch32 (ARM)",
                 address,
-            );
+            )
         } else {
-            println!(
-                "Address - Instruction address 0x{:016x}, Instruction set Aarch32 (Thumb)\n",
+            format!(
+                "Address - Instruction address 0x{:016x}, Instruction set Aarch32 (Thumb)",
                 address,
-            );
+            )
+        }
+    };
+    tracer_print(tracer, text, true);
+
+    if tracer.exception_address {
+        /* an exception is not the target of the branch before it */
+        if let Some(from) = tracer.pending_source.take() {
+            tracer_branch_record(tracer, BranchRecord { from, to: None });
+        }
+    } else if let Some(from) = tracer.pending_source.take() {
+        let text = format!("          (target of the taken branch at 0x{:016x})", from);
+        tracer_print(tracer, text, true);
+        tracer_branch_record(
+            tracer,
+            BranchRecord {
+                from,
+                to: Some(address),
+            },
+        );
+    }
+
+    if tracer.exception_address {
+        /* the exception is taken before the instruction at the address */
+        tracer.pc = None;
+        return;
+    }
+    tracer.pc = Some(address);
+
+    let target = tracer.nr_unresolved_branches != 0;
+    if target {
+        tracer.nr_unresolved_branches -= 1;
+        /* the trace unit pops its return stack even when the target of a return is traced */
+        if tracer.unresolved_return {
+            tracer.unresolved_return = false;
+            tracer.return_stack.pop_back();
         }
     }
+    if tracer.branch_broadcast {
+        let text = if target {
+            "          (target of the preceding taken branch)"
+        } else {
+            "          (not a branch target, a discontinuity in the program flow)"
+        };
+        tracer_print(tracer, String::from(text), true);
+    }
 }
 
 pub fn tracer_context(
@@ -269,58 +1361,354 @@ pub fn tracer_context(
     p: u8,
     el: u8,
     sf: u8,
-    ns: u8,
+    security: SecurityState,
     v: u8,
-    vmid: u8,
+    vmid: u32,
     c: i32,
     contextid: u32,
 ) {
     if p != 0 {
         tracer.ex_level = el;
         tracer.sixty_four_bit = sf != 0;
-        tracer.security = ns == 0;
+        tracer.security = security;
         if v != 0 {
-            tracer.vmid = vmid;
+            if tracer.vmidopt {
+                tracer.context_id_el2 = vmid;
+            } else {
+                tracer.vmid = vmid;
+            }
         }
         if c != 0 {
             tracer.context_id = contextid;
         }
     }
 
-    println!("Context - Context ID = 0x{:X},", tracer.context_id);
-    println!("          VMID = 0x{:X},", tracer.vmid);
-    println!("          Exception level = EL{},", tracer.ex_level);
-    println!(
-        "          Security = {},",
-        if tracer.security { "S" } else { "NS" }
+    let text = if tracer.vmidopt {
+        format!(
+            "Context - Context ID (EL1) = 0x{:X},\n          Context ID (EL2) = 0x{:X},",
+            tracer.context_id, tracer.context_id_el2
+        )
+    } else {
+        format!(
+            "Context - Context ID = 0x{:X},\n          VMID = 0x{:X},",
+            tracer.context_id, tracer.vmid
+        )
+    };
+    tracer_print(tracer, text, true);
+    let text = format!(
+        "          Exception level = EL{},\n          Security = {},",
+        tracer.ex_level,
+        tracer.security.name()
     );
-    println!(
+    tracer_print(tracer, text, true);
+    let text = format!(
         "          {}-bit instruction",
         if tracer.sixty_four_bit { 64 } else { 32 }
     );
+    tracer_print(tracer, text, true);
 }
 
 pub fn tracer_atom(tracer: &mut Etmv4Tracer, tp: AtomType) {
-    match tp {
-        AtomTypeE => {
-            println!("ATOM - E");
-        }
-        AtomTypeN => {
-            println!("ATOM - N");
+    tracer_p0_element(tracer, P0Atom(tp));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* an ETE with branch broadcast and the return stack enabled */
+    fn return_stack_tracer() -> Etmv4Tracer {
+        let mut tracer = Etmv4Tracer::new();
+        tracer_enable_ete(&mut tracer).unwrap();
+        let config = Etmv4Config {
+            trcconfigr: (1 << 12) | (1 << 3),
+            ..tracer.config
+        };
+        apply_config(&mut tracer, &config).unwrap();
+        tracer.sixty_four_bit = true;
+        tracer
+    }
+
+    fn set_address(tracer: &mut Etmv4Tracer, address: u64) {
+        tracer.address_register[0] = AddressRegister {
+            address,
+            is: AddrRegIs0,
+        };
+    }
+
+    /* the speculative elements, the atoms as E or N and the output by its text */
+    fn queued(tracer: &Etmv4Tracer) -> Vec<String> {
+        tracer
+            .spec_queue
+            .iter()
+            .map(|elem| match elem {
+                P0Atom(AtomTypeE) => String::from("E"),
+                P0Atom(AtomTypeN) => String::from("N"),
+                P0Text { text, .. } => text.clone(),
+                _ => String::from("P0"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn return_target_from_return_stack() {
+        let mut tracer = return_stack_tracer();
+        tracer.link_branches.push((0x1000, 4));
+
+        /* a branch with link at 0x1000 to 0x2000 */
+        set_address(&mut tracer, 0x1000);
+        tracer_source_address(&mut tracer);
+        assert_eq!(tracer.return_stack.len(), 1);
+        set_address(&mut tracer, 0x2000);
+        tracer_address(&mut tracer);
+
+        /* a return at 0x2010, whose target is not traced */
+        set_address(&mut tracer, 0x2010);
+        tracer_source_address(&mut tracer);
+        assert_eq!(tracer.nr_unresolved_branches, 1);
+
+        /* the next P0 element finds the target on the return stack */
+        tracer_atom(&mut tracer, AtomTypeE);
+        assert!(tracer.return_stack.is_empty());
+        assert_eq!(tracer.address_register[0].address, 0x1004);
+    }
+    #[test]
+    fn branch_without_target_and_empty_return_stack() {
+        let mut tracer = return_stack_tracer();
+
+        set_address(&mut tracer, 0x2000);
+        tracer_address(&mut tracer);
+        tracer_atom(&mut tracer, AtomTypeE);
+
+        /* the target of the taken branch is missing, it is not a return */
+        tracer_atom(&mut tracer, AtomTypeE);
+        assert_eq!(tracer.address_register[0].address, 0x2000);
+        assert_eq!(tracer.nr_unresolved_branches, 1);
+    }
+    #[test]
+    fn m_profile_exception_names() {
+        assert_eq!(exception_name(0x03, true), "HardFault");
+        assert_eq!(exception_name(0x17, true), "IRQ7");
+        assert_eq!(exception_name(0x20, true), "Reserved");
+        assert_eq!(exception_name(0x207, true), "Reserved");
+        assert_eq!(exception_name(0x208, true), "IRQ8");
+        assert_eq!(exception_name(0x3ef, true), "IRQ495");
+        assert_eq!(exception_name(0x3f0, true), "Reserved");
+    }
+    #[test]
+    fn transaction_start_after_speculative_p0_element() {
+        let mut tracer = Etmv4Tracer::new();
+        tracer_enable_ete(&mut tracer).unwrap();
+        tracer.max_spec_depth = 4;
+
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_transaction_start(&mut tracer);
+        /* the transaction start waits for the atom, and is not a P0 element */
+        assert_eq!(tracer.transaction_depth, 0);
+        assert_eq!(spec_depth(&tracer), 1);
+
+        tracer_commit(&mut tracer, 1);
+        assert_eq!(tracer.transaction_depth, 1);
+        assert!(tracer.spec_queue.is_empty());
+    }
+    #[test]
+    fn output_waits_for_speculative_p0_elements() {
+        let mut tracer = Etmv4Tracer::new();
+        tracer.max_spec_depth = 4;
+
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_ts(&mut tracer, 5, false, 0, 64);
+        set_address(&mut tracer, 0x2000);
+        tracer_address(&mut tracer);
+        tracer_atom(&mut tracer, AtomTypeN);
+        assert_eq!(
+            queued(&tracer),
+            [
+                "E",
+                "Timestamp - 5",
+                "Address - Instruction address 0x0000000000002000, Instruction set Aarch32 (Thumb)",
+                "N"
+            ]
+        );
+
+        /* the output is printed after the atom before it, and before the atom after it */
+        tracer_commit(&mut tracer, 1);
+        assert_eq!(queued(&tracer), ["N"]);
+
+        /* with nothing speculative the output is printed right away */
+        tracer_commit(&mut tracer, 1);
+        tracer_event(&mut tracer, 0x1);
+        assert!(tracer.spec_queue.is_empty());
+
+        /* so it is when the P0 elements are printed as they arrive */
+        tracer.show_speculative = true;
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_cc(&mut tracer, false, 3);
+        assert_eq!(queued(&tracer), ["E"]);
+    }
+    #[test]
+    fn output_after_canceled_p0_elements() {
+        let mut tracer = Etmv4Tracer::new();
+        tracer.max_spec_depth = 4;
+
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_atom(&mut tracer, AtomTypeE);
+        set_address(&mut tracer, 0x2000);
+        tracer_address(&mut tracer);
+        tracer_cc(&mut tracer, false, 3);
+
+        /* the address is about the canceled atom, but the cycle count is not */
+        tracer_cancel(&mut tracer, 1);
+        assert_eq!(
+            queued(&tracer),
+            ["E", "Cycle count - 3 cycles, applies to 0 P0 element(s)"]
+        );
+
+        /* it is printed as soon as no speculative P0 element is before it */
+        tracer_cancel(&mut tracer, 1);
+        assert!(tracer.spec_queue.is_empty());
+
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_event(&mut tracer, 0x2);
+        tracer_discard(&mut tracer);
+        assert!(tracer.spec_queue.is_empty());
+    }
+    #[test]
+    fn exception_flushes_c_elements() {
+        let mut tracer = Etmv4Tracer::new();
+        tracer.max_spec_depth = 4;
+
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_exception(&mut tracer, 0x0e);
+        assert_eq!(queued(&tracer), ["E", "P0"]);
+
+        tracer_cond_inst(&mut tracer, 1);
+        tracer_exception(&mut tracer, 0x0e);
+        assert_eq!(
+            queued(&tracer),
+            [
+                "E",
+                "P0",
+                "Conditional flush - 1 C element(s) without result discarded",
+                "P0"
+            ]
+        );
+        assert!(tracer.cond_queue.is_empty());
+    }
+    #[test]
+    fn configure_from_id_registers() {
+        let mut tracer = Etmv4Tracer::new();
+        let config = Etmv4Config {
+            /* CONDTYPE = APSR, COMMOPT = 1 */
+            trcidr0: (1 << 12) | (1 << 29),
+            trcidr8: 8,
+            trcidr9: 16,
+            trcidr10: 2,
+            trcidr11: 1,
+            trcidr12: 5,
+            trcidr13: 2,
+            /* BB and RS */
+            trcconfigr: (1 << 3) | (1 << 12),
+            ..Etmv4Config::new()
+        };
+        tracer_configure(&mut tracer, &config).unwrap();
+        assert_eq!(tracer.condtype, CONDTYPE_APSR);
+        assert_eq!(tracer.commopt, 1);
+        assert_eq!(tracer.max_spec_depth, 8);
+        assert_eq!(tracer.p0_key_max, 16);
+        assert_eq!(tracer.p1_key_max, 2);
+        assert_eq!(tracer.p1_spc_key_max, 1);
+        assert_eq!(tracer.cond_key_max_incr, 3);
+        assert!(tracer.branch_broadcast && tracer.return_stack_enabled);
+        assert_eq!((tracer.context_id_size, tracer.vmid_size), (4, 1));
+
+        /* the tracers of the other trace streams get the same configuration */
+        let other = tracer.init_new();
+        assert_eq!(other.max_spec_depth, 8);
+        assert_eq!(other.cond_key_max_incr, 3);
+
+        /* CIDSIZE and VMIDSIZE only take the sizes the architecture defines */
+        let config = Etmv4Config {
+            trcidr2: 2 << 5,
+            ..Etmv4Config::new()
+        };
+        assert!(tracer_configure(&mut tracer, &config).is_err());
+        let config = Etmv4Config {
+            trcidr2: 3 << 10,
+            ..Etmv4Config::new()
+        };
+        assert!(tracer_configure(&mut tracer, &config).is_err());
+        assert_eq!(tracer.max_spec_depth, 8);
+    }
+    /* an ETMv4 with the return stack enabled and the image of an AArch64 program */
+    fn image_tracer() -> Etmv4Tracer {
+        let mut tracer = Etmv4Tracer::new();
+        let config = Etmv4Config {
+            trcconfigr: 1 << 12,
+            ..tracer.config
+        };
+        apply_config(&mut tracer, &config).unwrap();
+        tracer.sixty_four_bit = true;
+        tracer.max_spec_depth = 8;
+
+        /* bl 0x2000, nop, b 0x1000 */
+        let caller = [0x94000400u32, 0xd503201f, 0x17fffffe];
+        /* nop, ret */
+        let callee = [0xd503201fu32, 0xd65f03c0];
+        for (address, code) in [(0x1000, &caller[..]), (0x2000, &callee[..])] {
+            let data = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+            tracer.image.add_region(address, data);
         }
+        tracer
     }
 
-    /*
-     * If p0_key_max is zero, it implies that the target CPU uses no P0 right-hand keys.
-     * If so, there is no need to update p0_key.
-     */
-    if (tracer.p0_key_max) != 0 {
-        tracer.p0_key += 1;
-        tracer.p0_key %= tracer.p0_key_max;
+    #[test]
+    fn return_target_from_the_image() {
+        let mut tracer = image_tracer();
+        set_address(&mut tracer, 0x1000);
+        tracer_address(&mut tracer);
+
+        /* the branch with link pushes its return address */
+        tracer_atom(&mut tracer, AtomTypeE);
+        assert_eq!(tracer.pc, Some(0x2000));
+        assert_eq!(tracer.return_stack.len(), 1);
+
+        /* the return has no address packet, so its target is popped at the next P0 element */
+        tracer_atom(&mut tracer, AtomTypeE);
+        assert_eq!(tracer.nr_unresolved_branches, 1);
+        tracer_atom(&mut tracer, AtomTypeN);
+        assert!(tracer.return_stack.is_empty());
+        assert_eq!(tracer.address_register[0].address, 0x1004);
+        assert_eq!(tracer.pc, Some(0x100c));
     }
+    #[test]
+    fn traced_return_target_pops_the_return_stack() {
+        let mut tracer = image_tracer();
+        set_address(&mut tracer, 0x1000);
+        tracer_address(&mut tracer);
+        tracer_atom(&mut tracer, AtomTypeE);
+        tracer_atom(&mut tracer, AtomTypeE);
 
-    tracer.curr_spec_depth += 1;
-    if tracer.max_spec_depth == 0 || (tracer.curr_spec_depth > tracer.max_spec_depth) {
-        tracer_commit(tracer, 1);
+        /* the target is not the one on the return stack, which is popped all the same */
+        set_address(&mut tracer, 0x3000);
+        tracer_address(&mut tracer);
+        assert_eq!(tracer.nr_unresolved_branches, 0);
+        assert!(tracer.return_stack.is_empty());
+        assert_eq!(tracer.pc, Some(0x3000));
+
+        /* no branch is known outside the image */
+        tracer_atom(&mut tracer, AtomTypeE);
+        assert_eq!(tracer.pc, None);
+        assert_eq!(tracer.nr_unresolved_branches, 0);
+    }
+    #[test]
+    fn full_return_stack_loses_the_oldest_entry() {
+        let mut tracer = return_stack_tracer();
+        for i in 0..=RETURN_STACK_DEPTH as u64 {
+            tracer_return_stack_push(&mut tracer, 0x1000 + 4 * i, AddrRegIs0);
+        }
+        assert_eq!(tracer.return_stack.len(), RETURN_STACK_DEPTH);
+        assert_eq!(tracer.return_stack.front().unwrap().address, 0x1004);
+        assert_eq!(tracer.return_stack.back().unwrap().address, 0x1040);
     }
 }
@@ -0,0 +1,367 @@
+/* the reasons in the qual_status field of the support packet */
+const QUAL_STATUS: [&str; 4] = [
+    "No change to filter qualification",
+    "Qualification ended, preceding te_inst sent explicitly",
+    "One or more instruction trace packets lost",
+    "Qualification ended, preceding te_inst would not have been sent",
+];
+
+/* the instruction trace options of the support packet, from bit 0 of ioptions */
+const IOPTIONS: [&str; 5] = [
+    "implicit return",
+    "implicit exception",
+    "full address",
+    "jump target cache",
+    "branch prediction",
+];
+
+/* what follows the correctly predicted branches of a branch count packet, by branch_fmt */
+pub const BRANCH_FMT: [&str; 4] = [
+    "the next branch is mispredicted",
+    "reserved",
+    "the address which follows is of a correct prediction if it is a branch",
+    "the address which follows is of a mispredicted branch",
+];
+
+pub const IOPTION_FULL_ADDRESS: u32 = 1 << 2;
+
+/*
+ * The widths of the packet fields, which are parameters of the encoder. The default is the
+ * profile of an RV32 encoder with U, S and M privileges, 6 bits of ecause, no context or time
+ * in the synchronization packets, a jump target cache of 256 entries, no implicit return stack
+ * or call counter and the 5 standard instruction trace options.
+ */
+#[derive(Clone, Copy)]
+pub struct EtraceConfig {
+    pub iaddress_width: u32,
+    pub iaddress_lsb: u32,
+    pub privilege_width: u32,
+    pub ecause_width: u32,
+    pub context_width: u32,
+    pub time_width: u32,
+    /* the jump target cache has 2^cache_size entries */
+    pub cache_size: u32,
+    pub ioptions_width: u32,
+    /* the implicit return stack has 2^return_stack_size entries, there is none when it is 0 */
+    pub return_stack_size: u32,
+    pub call_counter_size: u32,
+}
+
+impl EtraceConfig {
+    pub fn new() -> Self {
+        Self {
+            iaddress_width: 32,
+            iaddress_lsb: 1,
+            privilege_width: 2,
+            ecause_width: 6,
+            context_width: 0,
+            time_width: 0,
+            cache_size: 8,
+            ioptions_width: 5,
+            return_stack_size: 0,
+            call_counter_size: 0,
+        }
+    }
+}
+
+/* check that every field fits the value it is read into */
+pub fn etrace_check_config(config: &EtraceConfig) -> Result<(), String> {
+    if config.iaddress_lsb >= config.iaddress_width {
+        return Err(format!(
+            "Invalid E-Trace iaddress_lsb {}: expect less than the address width {}",
+            config.iaddress_lsb, config.iaddress_width
+        ));
+    }
+    for (name, width, max) in [
+        ("privilege width", config.privilege_width, 8),
+        ("ecause width", config.ecause_width, 32),
+        ("context width", config.context_width, 64),
+        ("time width", config.time_width, 64),
+        ("cache size", config.cache_size, 16),
+        ("ioptions width", config.ioptions_width, 32),
+        ("return stack size", config.return_stack_size, 15),
+        ("call counter size", config.call_counter_size, 16),
+    ] {
+        if width > max {
+            return Err(format!(
+                "Invalid E-Trace {} {}: expect 0 to {}",
+                name, width, max
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub struct EtraceTracer {
+    pub config: EtraceConfig,
+    /* Trace analyzer state between receiving packets */
+    pub address: u64,
+    pub privilege: u8,
+    pub context: u64,
+    pub ioptions: u32,
+    /* no address is known until the first synchronization packet */
+    pub synced: bool,
+    /* the direct-mapped jump target cache, indexed by the low order bits of the address */
+    pub jump_targets: Vec<Option<u64>>,
+}
+
+impl EtraceTracer {
+    pub fn new() -> Self {
+        let config = EtraceConfig::new();
+        Self {
+            config,
+            address: 0,
+            privilege: 0,
+            context: 0,
+            ioptions: 0,
+            synced: false,
+            jump_targets: vec![None; 1 << config.cache_size],
+        }
+    }
+
+    /* the configuration is final by now, so the jump target cache can be sized from it */
+    pub fn init_new(&self) -> Self {
+        Self {
+            config: self.config,
+            jump_targets: vec![None; 1 << self.config.cache_size],
+            ..Self::new()
+        }
+    }
+}
+
+fn privilege_name(privilege: u8) -> &'static str {
+    match privilege {
+        0 => "U",
+        1 => "S",
+        3 => "M",
+        _ => "Reserved",
+    }
+}
+
+fn address_mask(tracer: &EtraceTracer) -> u64 {
+    if tracer.config.iaddress_width >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << tracer.config.iaddress_width) - 1
+    }
+}
+
+fn jump_target_index(tracer: &EtraceTracer, address: u64) -> usize {
+    ((address >> tracer.config.iaddress_lsb) & ((1u64 << tracer.config.cache_size) - 1)) as usize
+}
+
+/* the address is differential to the last one unless the full address option is on */
+fn tracer_etrace_update_address(tracer: &mut EtraceTracer, address: u64, full: bool) {
+    let address = address << tracer.config.iaddress_lsb;
+    if full || (tracer.ioptions & IOPTION_FULL_ADDRESS) != 0 {
+        tracer.address = address & address_mask(tracer);
+    } else {
+        tracer.address = tracer.address.wrapping_add(address) & address_mask(tracer);
+    }
+}
+
+fn tracer_etrace_print_address(
+    tracer: &EtraceTracer,
+    notify: bool,
+    updiscon: bool,
+    irdepth: Option<u32>,
+) {
+    if tracer.synced {
+        println!("Address - 0x{:016x}", tracer.address);
+    } else {
+        println!("Address - unknown until the next synchronization packet");
+    }
+    if notify {
+        println!("          Notification, the address was requested explicitly");
+    }
+    if updiscon {
+        println!("          Uninferable discontinuity before the previous instruction");
+    }
+    if let Some(depth) = irdepth {
+        println!(
+            "          Return stack depth {}, a return was not predicted or a trap follows",
+            depth
+        );
+    }
+}
+
+/* 0 in the branch map is a taken branch, 1 one which is not taken, the oldest in bit 0 */
+pub fn tracer_etrace_branch_map(_: &EtraceTracer, nr_branches: u32, map: u64) {
+    for i in 0..nr_branches {
+        if (map & (1 << i)) == 0 {
+            println!("ATOM - E");
+        } else {
+            println!("ATOM - N");
+        }
+    }
+}
+
+/*
+ * The address of a format 1 or 2 packet is the target of an uninferable jump unless it was
+ * requested explicitly. The address of a branch count packet is that of a branch.
+ */
+pub fn tracer_etrace_address(
+    tracer: &mut EtraceTracer,
+    address: u64,
+    notify: bool,
+    updiscon: bool,
+    irdepth: Option<u32>,
+    jump: bool,
+) {
+    tracer_etrace_update_address(tracer, address, false);
+    if tracer.synced && jump && !notify {
+        /* the encoder sends the index instead when the address is next in the cache */
+        let index = jump_target_index(tracer, tracer.address);
+        if let Some(entry) = tracer.jump_targets.get_mut(index) {
+            *entry = Some(tracer.address);
+        }
+    }
+    tracer_etrace_print_address(tracer, notify, updiscon, irdepth);
+}
+
+pub fn tracer_etrace_branch_count(_: &EtraceTracer, count: u32, fmt: u8) {
+    println!(
+        "Branch count - {} correctly predicted branches, {}",
+        count, BRANCH_FMT[fmt as usize]
+    );
+}
+
+pub fn tracer_etrace_jump_target(tracer: &mut EtraceTracer, index: u32) {
+    match tracer.jump_targets.get(index as usize).copied().flatten() {
+        Some(address) if tracer.synced => {
+            tracer.address = address;
+            println!(
+                "Jump target index - {}, Address 0x{:016x}",
+                index, tracer.address
+            );
+        }
+        _ => {
+            /* the entry was filled before the decode started or was lost with the trace */
+            tracer.synced = false;
+            println!(
+                "Jump target index - {}, Address unknown until the next synchronization packet",
+                index
+            );
+        }
+    }
+}
+
+pub fn tracer_etrace_sync_start(
+    tracer: &mut EtraceTracer,
+    branch: bool,
+    privilege: u8,
+    context: Option<u64>,
+    address: u64,
+) {
+    tracer.privilege = privilege;
+    if let Some(context) = context {
+        tracer.context = context;
+    }
+    tracer_etrace_update_address(tracer, address, true);
+    tracer.synced = true;
+
+    println!("Sync start - Address 0x{:016x},", tracer.address);
+    println!(
+        "             Privilege = {}, Context = 0x{:X},",
+        privilege_name(tracer.privilege),
+        tracer.context
+    );
+    println!(
+        "             The instruction is {}",
+        if branch {
+            "not a taken branch"
+        } else {
+            "a taken branch"
+        }
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn tracer_etrace_sync_trap(
+    tracer: &mut EtraceTracer,
+    branch: bool,
+    privilege: u8,
+    context: Option<u64>,
+    ecause: u32,
+    interrupt: bool,
+    thaddr: bool,
+    address: u64,
+    tval: u64,
+) {
+    tracer.privilege = privilege;
+    if let Some(context) = context {
+        tracer.context = context;
+    }
+    tracer_etrace_update_address(tracer, address, true);
+    tracer.synced = true;
+
+    println!(
+        "Sync trap - {} {}, tval = 0x{:X},",
+        if interrupt { "Interrupt" } else { "Exception" },
+        ecause,
+        tval
+    );
+    println!(
+        "            Address 0x{:016x} is the {},",
+        tracer.address,
+        if thaddr {
+            "trap handler"
+        } else {
+            "trapping instruction"
+        }
+    );
+    println!(
+        "            Privilege = {}, Context = 0x{:X}",
+        privilege_name(tracer.privilege),
+        tracer.context
+    );
+    if !branch {
+        println!("            The last instruction before the trap was a taken branch");
+    }
+}
+
+pub fn tracer_etrace_sync_context(tracer: &mut EtraceTracer, privilege: u8, context: Option<u64>) {
+    tracer.privilege = privilege;
+    if let Some(context) = context {
+        tracer.context = context;
+    }
+
+    println!(
+        "Sync context - Privilege = {}, Context = 0x{:X}",
+        privilege_name(tracer.privilege),
+        tracer.context
+    );
+}
+
+pub fn tracer_etrace_support(
+    tracer: &mut EtraceTracer,
+    ienable: bool,
+    encoder_mode: u32,
+    qual_status: u8,
+    ioptions: u32,
+) {
+    tracer.ioptions = ioptions;
+
+    println!(
+        "Support - Instruction trace {}, Encoder mode {},",
+        if ienable { "enabled" } else { "disabled" },
+        encoder_mode
+    );
+    println!("          {},", QUAL_STATUS[qual_status as usize]);
+    let options: Vec<&str> = IOPTIONS
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| (ioptions & (1 << i)) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if options.is_empty() {
+        println!("          No instruction trace options");
+    } else {
+        println!("          Options: {}", options.join(", "));
+    }
+    if qual_status == 2 {
+        /* trace was lost, the next address packet is no use until the next sync */
+        tracer.synced = false;
+        tracer.jump_targets.fill(None);
+    }
+}
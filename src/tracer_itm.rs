@@ -0,0 +1,198 @@
+/* the number of stimulus ports, 32 in each of 8 pages */
+const NR_STIMULUS_PORTS: usize = 256;
+
+/* the DWT event counters, in the bit order of the event counter packet */
+const EVENT_COUNTERS: [&str; 6] = ["CPI", "EXC", "SLEEP", "LSU", "FOLD", "CYC"];
+
+/* the Function field of the exception trace packet */
+const EXCEPTION_FUNCTION: [&str; 4] = ["Reserved", "Entered", "Exited", "Returned to"];
+
+/* the meaning of TC in local timestamp packets */
+const TIMESTAMP_CONTROL: [&str; 4] = [
+    "in sync with the packet",
+    "delayed relative to the packet",
+    "the packet is delayed relative to the event",
+    "both the timestamp and the packet are delayed",
+];
+
+pub struct ItmTracer {
+    /* Trace analyzer state between receiving packets */
+    pub page: u8,
+    pub local_timestamp: u64,
+    pub global_timestamp: u64,
+    /* the text written to each stimulus port which has not been printed yet */
+    pub port_text: Vec<String>,
+}
+
+impl ItmTracer {
+    pub fn new() -> Self {
+        Self {
+            page: 0,
+            local_timestamp: 0,
+            global_timestamp: 0,
+            port_text: vec![String::new(); NR_STIMULUS_PORTS],
+        }
+    }
+
+    pub fn init_new(&self) -> Self {
+        Self::new()
+    }
+}
+
+fn exception_name(number: u16) -> String {
+    match number {
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        7 => "SecureFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMonitor".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        16.. => format!("IRQ {}", number - 16),
+        _ => "Reserved".to_string(),
+    }
+}
+
+pub fn tracer_itm_sync(tracer: &mut ItmTracer) {
+    tracer.page = 0;
+    println!("Synchronization");
+}
+
+pub fn tracer_itm_overflow(_: &ItmTracer) {
+    println!("Overflow - packets were lost");
+}
+
+pub fn tracer_itm_local_timestamp(tracer: &mut ItmTracer, delta: u32, tc: u8) {
+    /* a local timestamp counts the cycles since the previous one */
+    tracer.local_timestamp += delta as u64;
+    println!(
+        "Local timestamp - {} (+{}), {}",
+        tracer.local_timestamp, delta, TIMESTAMP_CONTROL[tc as usize]
+    );
+}
+
+/*
+ * A global timestamp is sent in two packets, GTS1 with bits[25:0] and GTS2 with the bits above.
+ * nr_bits low order bits from bit lsb upwards are replaced.
+ */
+pub fn tracer_itm_global_timestamp(
+    tracer: &mut ItmTracer,
+    value: u64,
+    lsb: u32,
+    nr_bits: u32,
+    clock_change: bool,
+    wrap: bool,
+) {
+    let mask = ((1u64 << nr_bits) - 1) << lsb;
+    tracer.global_timestamp = (tracer.global_timestamp & !mask) | ((value << lsb) & mask);
+
+    println!(
+        "Global timestamp - {}{}{}",
+        tracer.global_timestamp,
+        if clock_change {
+            ", the clock changed"
+        } else {
+            ""
+        },
+        if wrap {
+            ", the high order bits have changed"
+        } else {
+            ""
+        }
+    );
+}
+
+pub fn tracer_itm_extension(tracer: &mut ItmTracer, value: u32, hardware: bool) {
+    if hardware {
+        println!("Extension - hardware source 0x{:X}", value);
+    } else {
+        tracer.page = (value & 0x7) as u8;
+        println!("Extension - stimulus port page {}", tracer.page);
+    }
+}
+
+pub fn tracer_itm_instrumentation(tracer: &mut ItmTracer, port: u8, size: usize, data: u32) {
+    let port = tracer.page as usize * 32 + port as usize;
+
+    println!(
+        "Instrumentation - Port {}, Data 0x{:0width$X}",
+        port,
+        data,
+        width = size * 2
+    );
+
+    /* printf-style output is usually written a byte at a time, collect it into lines */
+    if size == 1 {
+        match data as u8 {
+            b'\n' => {
+                println!("Port {} output - {}", port, tracer.port_text[port]);
+                tracer.port_text[port].clear();
+            }
+            b'\r' => {}
+            c => tracer.port_text[port].push(c as char),
+        }
+    }
+}
+
+/* print the text of the ports whose last line was not ended by a newline */
+pub fn tracer_itm_flush(tracer: &mut ItmTracer) {
+    for (port, text) in tracer.port_text.iter_mut().enumerate() {
+        if !text.is_empty() {
+            println!("Port {} output - {}", port, text);
+            text.clear();
+        }
+    }
+}
+
+pub fn tracer_itm_event_counter(_: &ItmTracer, counters: u8) {
+    for (i, name) in EVENT_COUNTERS.iter().enumerate() {
+        if (counters & (1 << i)) != 0 {
+            println!("Event counter - {} counter wrapped", name);
+        }
+    }
+}
+
+pub fn tracer_itm_exception(_: &ItmTracer, number: u16, function: u8) {
+    println!(
+        "Exception - {} exception {} ({})",
+        EXCEPTION_FUNCTION[function as usize],
+        number,
+        exception_name(number)
+    );
+}
+
+pub fn tracer_itm_pc_sample(_: &ItmTracer, pc: Option<u32>) {
+    match pc {
+        Some(pc) => println!("PC sample - 0x{:08x}", pc),
+        None => println!("PC sample - the PE is sleeping"),
+    }
+}
+
+pub fn tracer_itm_data_pc(_: &ItmTracer, comparator: u8, pc: u32) {
+    println!("Data trace - Comparator {}, PC 0x{:08x}", comparator, pc);
+}
+
+pub fn tracer_itm_data_address(_: &ItmTracer, comparator: u8, offset: u16) {
+    println!(
+        "Data trace - Comparator {}, Address offset 0x{:04x}",
+        comparator, offset
+    );
+}
+
+pub fn tracer_itm_data_value(_: &ItmTracer, comparator: u8, write: bool, size: usize, data: u32) {
+    println!(
+        "Data trace - Comparator {}, {} 0x{:0width$X}",
+        comparator,
+        if write { "Write" } else { "Read" },
+        data,
+        width = size * 2
+    );
+}
+
+pub fn tracer_itm_hardware(_: &ItmTracer, id: u8, data: u32) {
+    println!("Hardware source {} - Data 0x{:X}", id, data);
+}
@@ -0,0 +1,254 @@
+use crate::tracer_ptm::PtmAtomType::*;
+use crate::tracer_ptm::PtmInstSet::*;
+
+/* exception numbers in the branch address packet, as PFT and ETMv3 number them */
+const PTM_EXP_NAME: [Option<&str>; 16] = [
+    Some("No exception"),
+    Some("Debug halt"),
+    Some("SMC"),
+    Some("Hyp trap"),
+    Some("Asynchronous data abort"),
+    Some("Jazelle/ThumbEE check"),
+    None,
+    None,
+    Some("Processor reset"),
+    Some("Undefined instruction"),
+    Some("SVC"),
+    Some("Prefetch abort or software breakpoint"),
+    Some("Synchronous data abort"),
+    Some("Generic"),
+    Some("IRQ"),
+    Some("FIQ"),
+];
+
+/* the reasons for an I-sync packet, in the information byte */
+pub const ISYNC_REASON: [&str; 4] = [
+    "Periodic",
+    "Tracing enabled",
+    "Tracing restarted after an overflow",
+    "Exit from debug state",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PtmInstSet {
+    Arm,
+    Thumb,
+    Jazelle,
+}
+
+pub enum PtmAtomType {
+    PtmAtomE,
+    PtmAtomN,
+}
+
+/* the trace analyzer state of PTM and of ETMv3, which shares most of its packets with PTM */
+pub struct PtmTracer {
+    /* size in bytes of the context ID, as programmed in ETMCR.ContextIDSize */
+    pub context_id_size: u32,
+    /* ETMCR.CycleAccurate: the packets carry cycle counts or, in ETMv3, P-headers count cycles */
+    pub cycle_accurate: bool,
+    /* Trace analyzer state between receiving packets */
+    pub address: u32,
+    pub inst_set: PtmInstSet,
+    pub context_id: u32,
+    pub vmid: u8,
+    pub timestamp: u64,
+    pub ns: bool,
+    pub hyp: bool,
+    pub alt_isa: bool,
+    /* ETMv3: the address of the load or store in progress at the most recent I-sync */
+    pub lsip_address: Option<u32>,
+}
+
+impl PtmTracer {
+    pub fn new() -> Self {
+        Self {
+            context_id_size: 4,
+            cycle_accurate: false,
+            address: 0,
+            inst_set: Arm,
+            context_id: 0,
+            vmid: 0,
+            timestamp: 0,
+            ns: false,
+            hyp: false,
+            alt_isa: false,
+            lsip_address: None,
+        }
+    }
+
+    /* create a tracer for another trace stream with the same configuration */
+    pub fn init_new(&self) -> Self {
+        Self {
+            context_id_size: self.context_id_size,
+            cycle_accurate: self.cycle_accurate,
+            ..Self::new()
+        }
+    }
+}
+
+pub fn ptm_exception_name(number: u16) -> &'static str {
+    PTM_EXP_NAME
+        .get(number as usize)
+        .copied()
+        .flatten()
+        .unwrap_or("Reserved")
+}
+
+pub fn inst_set_name(inst_set: PtmInstSet) -> &'static str {
+    match inst_set {
+        Arm => "ARM",
+        Thumb => "Thumb",
+        Jazelle => "Jazelle",
+    }
+}
+
+pub fn tracer_ptm_async(_: &PtmTracer) {
+    println!("A-sync");
+}
+
+/* lsip_address is the address which an ETMv3 I-sync packet with load/store in progress carries */
+pub fn tracer_ptm_isync(
+    tracer: &mut PtmTracer,
+    address: u32,
+    inst_set: PtmInstSet,
+    info: u8,
+    cycle_count: Option<u32>,
+    context_id: Option<u32>,
+    lsip_address: Option<u32>,
+) {
+    tracer.address = address;
+    tracer.inst_set = inst_set;
+    tracer.ns = (info & 0x08) != 0;
+    tracer.alt_isa = (info & 0x04) != 0;
+    tracer.hyp = (info & 0x02) != 0;
+    tracer.lsip_address = lsip_address;
+    if let Some(context_id) = context_id {
+        tracer.context_id = context_id;
+    }
+
+    println!("I-sync - {},", ISYNC_REASON[((info >> 5) & 0x3) as usize]);
+    println!(
+        "         Instruction address 0x{:08x}, Instruction set {},",
+        tracer.address,
+        inst_set_name(tracer.inst_set)
+    );
+    println!(
+        "         Security = {}{}{},",
+        if tracer.ns { "NS" } else { "S" },
+        if tracer.hyp { ", Hyp mode" } else { "" },
+        if tracer.alt_isa { ", ThumbEE" } else { "" }
+    );
+    if let Some(count) = cycle_count {
+        println!("         Cycle count = {},", count);
+    }
+    if let Some(address) = tracer.lsip_address {
+        println!("         Load/store in progress address 0x{:08x},", address);
+    }
+    println!("         Context ID = 0x{:X}", tracer.context_id);
+}
+
+pub fn tracer_ptm_atom(_: &PtmTracer, atom: PtmAtomType, cycle_count: Option<u32>) {
+    let atom = match atom {
+        PtmAtomE => "E",
+        PtmAtomN => "N",
+    };
+    match cycle_count {
+        Some(count) => println!("ATOM - {}, {} cycles", atom, count),
+        None => println!("ATOM - {}", atom),
+    }
+}
+
+pub fn tracer_ptm_branch_address(
+    tracer: &mut PtmTracer,
+    address: u32,
+    inst_set: PtmInstSet,
+    exception: Option<(u16, bool, bool, bool)>,
+    cycle_count: Option<u32>,
+) {
+    tracer.address = address;
+    tracer.inst_set = inst_set;
+
+    println!(
+        "Branch address - Instruction address 0x{:08x}, Instruction set {}",
+        tracer.address,
+        inst_set_name(tracer.inst_set)
+    );
+    if let Some((number, ns, hyp, cancel)) = exception {
+        tracer.ns = ns;
+        tracer.hyp = hyp;
+        let name = ptm_exception_name(number);
+        println!(
+            "                 Exception {} ({}), Security = {}{}",
+            number,
+            name,
+            if ns { "NS" } else { "S" },
+            if hyp { ", Hyp mode" } else { "" }
+        );
+        if cancel {
+            println!("                 The last instruction before the exception was canceled");
+        }
+    }
+    if let Some(count) = cycle_count {
+        println!("                 {} cycles", count);
+    }
+}
+
+pub fn tracer_ptm_waypoint_update(
+    tracer: &mut PtmTracer,
+    address: u32,
+    inst_set: PtmInstSet,
+    alt_isa: Option<bool>,
+) {
+    tracer.address = address;
+    tracer.inst_set = inst_set;
+    if let Some(alt_isa) = alt_isa {
+        tracer.alt_isa = alt_isa;
+    }
+
+    println!(
+        "Waypoint update - Instruction address 0x{:08x}, Instruction set {}",
+        tracer.address,
+        inst_set_name(tracer.inst_set)
+    );
+}
+
+pub fn tracer_ptm_trigger(_: &PtmTracer) {
+    println!("Trigger - A trigger event occurred");
+}
+
+pub fn tracer_ptm_context_id(tracer: &mut PtmTracer, context_id: u32) {
+    tracer.context_id = context_id;
+    println!("Context ID - 0x{:X}", tracer.context_id);
+}
+
+pub fn tracer_ptm_vmid(tracer: &mut PtmTracer, vmid: u8) {
+    tracer.vmid = vmid;
+    println!("VMID - 0x{:X}", tracer.vmid);
+}
+
+pub fn tracer_ptm_timestamp(
+    tracer: &mut PtmTracer,
+    timestamp: u64,
+    nr_bits: u32,
+    cycle_count: Option<u32>,
+) {
+    /* only the low order bits which the packet carries are replaced */
+    if nr_bits >= 64 {
+        tracer.timestamp = timestamp;
+    } else {
+        tracer.timestamp &= !((1u64 << nr_bits) - 1);
+        tracer.timestamp |= timestamp;
+    }
+
+    println!("Timestamp - {}", tracer.timestamp);
+    if let Some(count) = cycle_count {
+        println!("            {} cycles", count);
+    }
+}
+
+pub fn tracer_ptm_exception_return(_: &PtmTracer) {
+    println!("Exception return");
+}
+
+pub fn tracer_ptm_ignore(_: &PtmTracer) {}
@@ -0,0 +1,244 @@
+/* the events of the events packet and their bit numbers */
+const SPE_EVENTS: [(u32, &str); 15] = [
+    (0, "Generated exception"),
+    (1, "Retired"),
+    (2, "L1D access"),
+    (3, "L1D refill"),
+    (4, "TLB access"),
+    (5, "TLB walk"),
+    (6, "Not taken"),
+    (7, "Mispredicted"),
+    (8, "Last level cache access"),
+    (9, "Last level cache miss"),
+    (10, "Remote access"),
+    (11, "Misaligned access"),
+    (16, "Transactional"),
+    (17, "Partial predicate"),
+    (18, "Empty predicate"),
+];
+
+const SPE_COUNTERS: [&str; 3] = ["Total latency", "Issue latency", "Translation latency"];
+
+pub struct SpeTracer {
+    /* Trace analyzer state between receiving packets */
+    pub nr_records: u64,
+    pub timestamp: u64,
+}
+
+impl SpeTracer {
+    pub fn new() -> Self {
+        Self {
+            nr_records: 0,
+            timestamp: 0,
+        }
+    }
+
+    pub fn init_new(&self) -> Self {
+        Self::new()
+    }
+}
+
+/* the 56-bit virtual addresses are sign-extended from bit[55] */
+fn virtual_address(payload: u64) -> u64 {
+    let address = payload & 0x00ff_ffff_ffff_ffff;
+    if (address & (1 << 55)) != 0 {
+        address | 0xff00_0000_0000_0000
+    } else {
+        address
+    }
+}
+
+fn el_security(payload: u64) -> String {
+    format!(
+        "EL{}, {}",
+        (payload >> 61) & 0x3,
+        if (payload & (1 << 63)) != 0 {
+            "NS"
+        } else {
+            "S"
+        }
+    )
+}
+
+pub fn tracer_spe_end(tracer: &mut SpeTracer) {
+    tracer.nr_records += 1;
+    println!("End of record {}", tracer.nr_records);
+}
+
+/* a timestamp packet ends a record as well */
+pub fn tracer_spe_timestamp(tracer: &mut SpeTracer, timestamp: u64) {
+    tracer.timestamp = timestamp;
+    println!("Timestamp - {}", tracer.timestamp);
+    tracer_spe_end(tracer);
+}
+
+pub fn tracer_spe_address(_: &SpeTracer, index: u8, payload: u64) {
+    match index {
+        0 => println!(
+            "Address - PC 0x{:016x}, {}",
+            virtual_address(payload),
+            el_security(payload)
+        ),
+        1 => println!(
+            "Address - Branch target 0x{:016x}, {}",
+            virtual_address(payload),
+            el_security(payload)
+        ),
+        2 => println!(
+            "Address - Data virtual address 0x{:016x}, Tag 0x{:02X}",
+            virtual_address(payload),
+            payload >> 56
+        ),
+        3 => println!(
+            "Address - Data physical address 0x{:014x}, {}{}",
+            payload & 0x00ff_ffff_ffff_ffff,
+            if (payload & (1 << 63)) != 0 {
+                "NS"
+            } else {
+                "S"
+            },
+            if (payload & (1 << 62)) != 0 {
+                ", Checked access"
+            } else {
+                ""
+            }
+        ),
+        4 => println!(
+            "Address - Previous branch target 0x{:016x}, {}",
+            virtual_address(payload),
+            el_security(payload)
+        ),
+        _ => println!("Address - Index {}, 0x{:016x}", index, payload),
+    }
+}
+
+pub fn tracer_spe_counter(_: &SpeTracer, index: u8, count: u64) {
+    match SPE_COUNTERS.get(index as usize) {
+        Some(name) => println!("Counter - {} {} cycles", name, count),
+        None => println!("Counter - Counter {} {}", index, count),
+    }
+}
+
+pub fn tracer_spe_events(_: &SpeTracer, events: u64) {
+    let names: Vec<String> = (0..64)
+        .filter(|i| (events & (1 << i)) != 0)
+        .map(|i| match SPE_EVENTS.iter().find(|(bit, _)| *bit == i) {
+            Some((_, name)) => name.to_string(),
+            None => format!("Event {}", i),
+        })
+        .collect();
+    if names.is_empty() {
+        println!("Events - None");
+    } else {
+        println!("Events - {}", names.join(", "));
+    }
+}
+
+pub fn tracer_spe_data_source(_: &SpeTracer, source: u64) {
+    /* the data sources are IMPLEMENTATION DEFINED */
+    println!("Data source - 0x{:X}", source);
+}
+
+pub fn tracer_spe_context(_: &SpeTracer, index: u8, context: u32) {
+    println!("Context - CONTEXTIDR_EL{} = 0x{:X}", index + 1, context);
+}
+
+/*
+ * The class of the operation is in bits[1:0] of the header: 0 other, 1 load/store, 2 branch.
+ * The payload is its subclass.
+ */
+fn op_type_name(class: u8, payload: u8) -> String {
+    match class {
+        /* bit[0] of an SVE operation is not COND, the other operations are 0b0000000C */
+        0 if (payload & 0x89) == 0x08 => "Other, SVE".to_string(),
+        0 => format!(
+            "Other{}",
+            if (payload & 0x01) != 0 {
+                ", Conditional"
+            } else {
+                ""
+            }
+        ),
+        1 => {
+            let subclass = if (payload & 0xfe) == 0x00 {
+                "General purpose register".to_string()
+            } else if (payload & 0xfe) == 0x04 {
+                "SIMD&FP register".to_string()
+            } else if (payload & 0xe2) == 0x02 {
+                "Atomic".to_string()
+            } else if (payload & 0x0a) == 0x08 {
+                "SVE".to_string()
+            } else {
+                format!("Subclass 0x{:02X}", payload & 0xfe)
+            };
+            format!(
+                "{}, {}",
+                if (payload & 0x01) != 0 {
+                    "Store"
+                } else {
+                    "Load"
+                },
+                subclass
+            )
+        }
+        2 => format!(
+            "Branch{}{}",
+            if (payload & 0x01) != 0 {
+                ", Conditional"
+            } else {
+                ""
+            },
+            if (payload & 0x02) != 0 {
+                ", Indirect"
+            } else {
+                ""
+            }
+        ),
+        _ => format!("Reserved class, 0x{:02X}", payload),
+    }
+}
+
+pub fn tracer_spe_op_type(_: &SpeTracer, class: u8, payload: u8) {
+    println!("Operation - {}", op_type_name(class, payload));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_address_sign_extension() {
+        assert_eq!(
+            virtual_address(0x0000_7fff_1234_5678),
+            0x0000_7fff_1234_5678
+        );
+        assert_eq!(
+            virtual_address(0x0080_0000_0000_1000),
+            0xff80_0000_0000_1000
+        );
+        /* the EL and security bits are not part of the address */
+        assert_eq!(
+            virtual_address(0xa000_ffff_0000_0000),
+            0x0000_ffff_0000_0000
+        );
+        assert_eq!(
+            virtual_address(0xe0ff_ffff_ffff_fff0),
+            0xffff_ffff_ffff_fff0
+        );
+    }
+
+    #[test]
+    fn op_type_classes() {
+        assert_eq!(op_type_name(0, 0x00), "Other");
+        assert_eq!(op_type_name(0, 0x01), "Other, Conditional");
+        /* SVE with bit[1] (floating-point) and bit[2] (predicated) set */
+        assert_eq!(op_type_name(0, 0x0e), "Other, SVE");
+        assert_eq!(op_type_name(0, 0x09), "Other, Conditional");
+        assert_eq!(op_type_name(1, 0x01), "Store, General purpose register");
+        assert_eq!(op_type_name(1, 0x04), "Load, SIMD&FP register");
+        assert_eq!(op_type_name(1, 0x03), "Store, Atomic");
+        assert_eq!(op_type_name(1, 0x08), "Load, SVE");
+        assert_eq!(op_type_name(2, 0x03), "Branch, Conditional, Indirect");
+        assert_eq!(op_type_name(3, 0x00), "Reserved class, 0x00");
+    }
+}
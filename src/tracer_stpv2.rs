@@ -0,0 +1,168 @@
+pub struct Stpv2Tracer {
+    /* Trace analyzer state between receiving packets */
+    pub master: u16,
+    pub channel: u16,
+    /* the timestamp as it is transmitted, Gray coded if ts_gray is set */
+    pub timestamp: u64,
+    pub ts_gray: bool,
+    pub version: u8,
+}
+
+impl Stpv2Tracer {
+    pub fn new() -> Self {
+        Self {
+            master: 0,
+            channel: 0,
+            timestamp: 0,
+            ts_gray: false,
+            version: 0,
+        }
+    }
+
+    pub fn init_new(&self) -> Self {
+        Self::new()
+    }
+}
+
+/* a timestamp field of a packet: the updated low order bits and the number of them */
+pub struct Stpv2Timestamp {
+    pub value: u64,
+    pub nr_bits: u32,
+}
+
+fn gray_to_binary(gray: u64) -> u64 {
+    let mut binary = gray;
+    let mut shift = 1;
+    while shift < 64 {
+        binary ^= binary >> shift;
+        shift <<= 1;
+    }
+    binary
+}
+
+/* update the timestamp with the field of a packet and print it */
+fn tracer_stpv2_update_timestamp(tracer: &mut Stpv2Tracer, ts: Option<Stpv2Timestamp>) {
+    if let Some(ts) = ts {
+        if ts.nr_bits >= 64 {
+            tracer.timestamp = ts.value;
+        } else {
+            tracer.timestamp &= !((1u64 << ts.nr_bits) - 1);
+            tracer.timestamp |= ts.value;
+        }
+        println!(
+            "         Timestamp = {}",
+            if tracer.ts_gray {
+                gray_to_binary(tracer.timestamp)
+            } else {
+                tracer.timestamp
+            }
+        );
+    }
+}
+
+pub fn tracer_stpv2_async(tracer: &mut Stpv2Tracer) {
+    /* the master and the channel are unknown until the next M and C packets */
+    tracer.master = 0;
+    tracer.channel = 0;
+    println!("ASYNC");
+}
+
+pub fn tracer_stpv2_version(tracer: &mut Stpv2Tracer, version: u8) {
+    tracer.version = version;
+    tracer.master = 0;
+    tracer.channel = 0;
+    /* version 4 transmits the timestamps in Gray code, the others in natural binary */
+    tracer.ts_gray = version == 4;
+
+    println!(
+        "VERSION - {}, {} timestamps",
+        version,
+        if tracer.ts_gray {
+            "Gray code"
+        } else {
+            "natural binary"
+        }
+    );
+}
+
+pub fn tracer_stpv2_null(tracer: &mut Stpv2Tracer, ts: Option<Stpv2Timestamp>) {
+    if ts.is_some() {
+        println!("NULL_TS");
+        tracer_stpv2_update_timestamp(tracer, ts);
+    }
+}
+
+pub fn tracer_stpv2_master(tracer: &mut Stpv2Tracer, master: u16) {
+    tracer.master = master;
+    /* a new master starts at channel 0 */
+    tracer.channel = 0;
+    println!("Master - {}", tracer.master);
+}
+
+pub fn tracer_stpv2_channel(tracer: &mut Stpv2Tracer, channel: u16, nr_bits: u32) {
+    if nr_bits >= 16 {
+        tracer.channel = channel;
+    } else {
+        tracer.channel &= !((1u16 << nr_bits) - 1);
+        tracer.channel |= channel;
+    }
+    println!("Channel - {}", tracer.channel);
+}
+
+pub fn tracer_stpv2_error(_: &Stpv2Tracer, global: bool, error: u8) {
+    println!(
+        "{} - Error 0x{:02X}",
+        if global { "GERR" } else { "MERR" },
+        error
+    );
+}
+
+pub fn tracer_stpv2_data(
+    tracer: &mut Stpv2Tracer,
+    nr_bits: u32,
+    data: u64,
+    marker: bool,
+    ts: Option<Stpv2Timestamp>,
+) {
+    println!(
+        "D{}{}{} - Master {}, Channel {}, Data 0x{:0width$X}",
+        nr_bits,
+        if marker { "M" } else { "" },
+        if ts.is_some() { "TS" } else { "" },
+        tracer.master,
+        tracer.channel,
+        data,
+        width = (nr_bits / 4) as usize
+    );
+    tracer_stpv2_update_timestamp(tracer, ts);
+}
+
+pub fn tracer_stpv2_flag(tracer: &mut Stpv2Tracer, ts: Option<Stpv2Timestamp>) {
+    println!(
+        "FLAG{} - Master {}, Channel {}",
+        if ts.is_some() { "_TS" } else { "" },
+        tracer.master,
+        tracer.channel
+    );
+    tracer_stpv2_update_timestamp(tracer, ts);
+}
+
+pub fn tracer_stpv2_trigger(tracer: &mut Stpv2Tracer, data: u8, ts: Option<Stpv2Timestamp>) {
+    println!(
+        "TRIG{} - Master {}, Channel {}, Data 0x{:02X}",
+        if ts.is_some() { "_TS" } else { "" },
+        tracer.master,
+        tracer.channel,
+        data
+    );
+    tracer_stpv2_update_timestamp(tracer, ts);
+}
+
+pub fn tracer_stpv2_frequency(tracer: &mut Stpv2Tracer, freq: u32, ts: Option<Stpv2Timestamp>) {
+    println!(
+        "FREQ{} - Timestamp frequency {} Hz",
+        if ts.is_some() { "_TS" } else { "" },
+        freq
+    );
+    tracer_stpv2_update_timestamp(tracer, ts);
+}